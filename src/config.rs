@@ -2,11 +2,14 @@ use clap_conf::convert::Holder;
 use clap_conf::convert::Localizer;
 use clap_conf::env::Enver;
 use clap_conf::*;
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
 use simple_error::SimpleError;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io::Write;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
 use toml::Value;
@@ -15,6 +18,7 @@ use uuid::Uuid;
 pub struct Config {
     pub logfile: Option<String>,
     pub remap_file: Option<String>,
+    pub audio_language: Option<String>,
     pub bind_address: String,
     pub cache_directory: PathBuf,
     pub cache_timeout: u64,
@@ -22,7 +26,11 @@ pub struct Config {
     pub device_firmware: String,
     pub device_model: String,
     pub device_version: String,
+    pub disable_adaptive_bitrate: bool,
     pub disable_station_cache: bool,
+    pub disable_ssdp: bool,
+    pub reset_device_ids: bool,
+    pub ffmpeg: Option<String>,
     pub disable_donation_check: bool,
     pub multiplex: bool,
     pub no_tvc_guide_station: bool,
@@ -30,18 +38,150 @@ pub struct Config {
     pub override_zipcodes: Option<Vec<String>>,
     pub password: String,
     pub port: u16,
+    pub prefetch_segments: u8,
     pub quiet: bool,
     pub random_zipcode: bool,
     pub remap: bool,
     pub skip_hls: bool,
+    pub stall_retries: u8,
+    pub stall_timeout: u64,
+    pub stream_start: StartPolicy,
     pub rust_backtrace: bool,
     pub syslog: bool,
+    pub timeshift_max_size: u64,
+    pub timeshift_minutes: u64,
+    pub timeshift_stations: Option<Vec<String>>,
     pub tuner_count: u8,
+    pub tuners_per_service: bool,
+    pub udp_outputs: Option<Vec<UdpOutput>>,
     pub username: String,
     #[serde(skip_serializing)]
     pub uuid: String,
     pub verbose: u8,
+    // Tables have to come last, otherwise serializing to TOML fails
+    pub variant_policy: VariantPolicy,
+    pub profiles: HashMap<String, VariantPolicy>,
+    pub transcode: HashMap<String, TranscodeProfile>,
 }
+
+/// Policy that is used to pick a variant stream from a master playlist.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VariantPolicy {
+    #[serde(default)]
+    pub lowest: bool,
+    pub max_bandwidth: Option<u64>,
+    pub max_resolution: Option<usize>,
+    pub codec: Option<String>,
+    pub frame_rate: Option<f32>,
+}
+
+/// Where a new stream starts in the media playlist of a station. Starting close to the live edge
+/// makes tuning faster, while starting further back gives clients more to buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartPolicy {
+    /// Start with the newest segment
+    LiveEdge,
+    /// Start this many segments before the end of the playlist
+    SegmentsBack(usize),
+    /// Start with the oldest segment in the playlist
+    Full,
+}
+
+impl Default for StartPolicy {
+    fn default() -> Self {
+        StartPolicy::SegmentsBack(3)
+    }
+}
+
+impl std::str::FromStr for StartPolicy {
+    type Err = String;
+
+    /// Parse `live`, `full` or a number of segments
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "live" => Ok(StartPolicy::LiveEdge),
+            "full" => Ok(StartPolicy::Full),
+            _ => match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(StartPolicy::SegmentsBack(n)),
+                _ => Err(format!("Invalid stream start: {}", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for StartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartPolicy::LiveEdge => write!(f, "live"),
+            StartPolicy::SegmentsBack(n) => write!(f, "{}", n),
+            StartPolicy::Full => write!(f, "full"),
+        }
+    }
+}
+
+impl Serialize for StartPolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A station that is continuously pushed to a UDP (multicast) address, written as
+/// `<station id>=<address>:<port>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpOutput {
+    pub station: String,
+    pub address: SocketAddr,
+}
+
+impl std::str::FromStr for UdpOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((station, address)) if !station.trim().is_empty() => Ok(UdpOutput {
+                station: station.trim().to_owned(),
+                address: address
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid UDP output address: {}", address))?,
+            }),
+            _ => Err(format!("Invalid UDP output: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for UdpOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.station, self.address)
+    }
+}
+
+impl Serialize for UdpOutput {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Transcoding profile. `args` are the ffmpeg output options that are used to transcode a stream.
+#[derive(Default, Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TranscodeProfile {
+    pub args: String,
+}
+
+// File in the cache directory that holds the main UUID
+pub static UUID_FILE: &str = "uuid";
+
+// Transcoding profiles that HDHomeRun EXTEND devices support. Profiles in the config file with the
+// same name take precedence.
+static BUILTIN_TRANSCODE_PROFILES: [(&str, &str); 7] = [
+    ("heavy", "-c:v libx264 -preset veryfast -c:a aac -b:a 192k"),
+    ("mobile", "-c:v libx264 -preset veryfast -vf scale=-2:720 -r 30 -b:v 2000k -maxrate 2000k -bufsize 4000k -c:a aac -b:a 128k"),
+    ("internet720", "-c:v libx264 -preset veryfast -vf scale=-2:720 -b:v 1500k -maxrate 1500k -bufsize 3000k -c:a aac -b:a 128k"),
+    ("internet540", "-c:v libx264 -preset veryfast -vf scale=-2:540 -b:v 1000k -maxrate 1000k -bufsize 2000k -c:a aac -b:a 128k"),
+    ("internet480", "-c:v libx264 -preset veryfast -vf scale=-2:480 -b:v 800k -maxrate 800k -bufsize 1600k -c:a aac -b:a 96k"),
+    ("internet360", "-c:v libx264 -preset veryfast -vf scale=-2:360 -b:v 500k -maxrate 500k -bufsize 1000k -c:a aac -b:a 96k"),
+    ("internet240", "-c:v libx264 -preset veryfast -vf scale=-2:240 -b:v 250k -maxrate 250k -bufsize 500k -c:a aac -b:a 64k"),
+];
 impl Config {
     pub fn from_args_and_file() -> Result<Config, SimpleError> {
         let clap = clap_app!(
@@ -49,6 +189,7 @@ impl Config {
                 (version: crate_version!())
                 (author: "Wouter de Bie")
                 (about: "Locast to tuner")
+                (@arg audio_language: --audio_language +takes_value "Preferred audio language of streams with alternate audio (e.g. spa). Requires ffmpeg")
                 (@arg bind_address: -b --bind_address +takes_value "Bind address (default: 127.0.0.1)")
                 (@arg cache_dir: --cache_dir +takes_value "Cache directory (default: $HOME/.locast2tuner)")
                 (@arg cache_timeout: --cache_timeout +takes_value "Cache timeout (default: 3600)")
@@ -57,18 +198,36 @@ impl Config {
                 (@arg device_firmware: --device_firmware +takes_value "Device firmware (default: hdhomerun3_atsc)")
                 (@arg device_model: --device_model +takes_value "Device model (default: HDHR3-US)")
                 (@arg device_version: --device_version +takes_value "Device version (default: 20170612)")
+                (@arg disable_adaptive_bitrate: --disable_adaptive_bitrate "Don't switch to a lower variant stream when downloads fall behind")
                 (@arg disable_station_cache: --disable_station_cache "Disable stations cache")
+                (@arg disable_ssdp: --disable_ssdp "Don't announce tuners through SSDP")
+                (@arg ffmpeg: --ffmpeg +takes_value "Path to ffmpeg. Enables transcoding")
                 (@arg disable_donation_check: --disable_donation_check "Disable the donation check (use for Locast Cares accounts")
                 (@arg multiplex: -m --multiplex "Multiplex devices")
                 (@arg override_zipcodes: -z --override_zipcodes +takes_value "Override locations using zipcodes")
                 (@arg override_cities: --override_cities +takes_value "Override locations using cities")
                 (@arg password: -P --password +takes_value "Locast password")
                 (@arg port: -p --port +takes_value "Bind TCP port (default: 6077)")
+                (@arg prefetch_segments: --prefetch_segments +takes_value "Nr. of segments to download ahead of playback (default: 2)")
                 (@arg remap: -r --remap "Remap channels when multiplexed. Requires multiplex!")
+                (@arg reset_device_ids: --reset_device_ids "Forget the UUID and device IDs of all tuners and exit")
                 (@arg rust_backtrace: --rust_backtrace "Enable RUST_BACKTRACE=1")
+                (@arg stall_retries: --stall_retries +takes_value "Nr. of attempts to recover a stalled stream (default: 5)")
+                (@arg stall_timeout: --stall_timeout +takes_value "Seconds without new segments before a stream is recovered (default: 30)")
+                (@arg stream_start: --stream_start +takes_value "Where new streams start: live, full or a nr. of segments before the live edge (default: 3)")
                 (@arg syslog: --syslog "Log to syslogd")
+                (@arg timeshift_max_size: --timeshift_max_size +takes_value "Maximum size (MB) of the time-shift buffer of a station (default: 4096)")
+                (@arg timeshift_minutes: --timeshift_minutes +takes_value "Minutes of video to keep in time-shift buffers (default: 60)")
+                (@arg timeshift_stations: --timeshift_stations +takes_value "Station ids to keep a time-shift buffer for")
                 (@arg quiet: --quiet "Don't log to terminal")
                 (@arg tuner_count: --tuner_count +takes_value "Tuner count (default: 16)")
+                (@arg tuners_per_service: --tuners_per_service "Count tuners per location instead of globally")
+                (@arg udp_outputs: --udp_outputs +takes_value "Stations to push to UDP addresses (e.g. 1234=239.1.1.1:5000)")
+                (@arg variant_lowest: --variant_lowest "Pick the lowest quality variant stream")
+                (@arg variant_max_bandwidth: --variant_max_bandwidth +takes_value "Maximum bandwidth (bits/s) of the variant stream")
+                (@arg variant_max_resolution: --variant_max_resolution +takes_value "Maximum vertical resolution of the variant stream (e.g. 720)")
+                (@arg variant_codec: --variant_codec +takes_value "Preferred codec of the variant stream (e.g. avc1.4d)")
+                (@arg variant_frame_rate: --variant_frame_rate +takes_value "Preferred frame rate of the variant stream (e.g. 29.97)")
                 (@arg username: -U --username +takes_value "Locast username")
                 (@arg verbose: -v --verbose +takes_value "Verbosity (default: 0)")
                 (@arg logfile: -l --logfile +takes_value "Log file location")
@@ -169,6 +328,93 @@ impl Config {
             .conf("tuner_count")
             .t_def::<u8>(16);
 
+        conf.tuners_per_service = cfg.bool_flag("tuners_per_service", Filter::Arg)
+            || env_true_flag(&cfg, "l2t_tuners_per_service")
+            || cfg.bool_flag("tuners_per_service", Filter::Conf);
+
+        conf.prefetch_segments = cfg
+            .grab()
+            .arg("prefetch_segments")
+            .env("l2t_prefetch_segments")
+            .conf("prefetch_segments")
+            .t_def::<u8>(2);
+
+        conf.stall_retries = cfg
+            .grab()
+            .arg("stall_retries")
+            .env("l2t_stall_retries")
+            .conf("stall_retries")
+            .t_def::<u8>(5);
+
+        conf.stall_timeout = cfg
+            .grab()
+            .arg("stall_timeout")
+            .env("l2t_stall_timeout")
+            .conf("stall_timeout")
+            .t_def::<u64>(30);
+
+        conf.stream_start = cfg
+            .grab()
+            .arg("stream_start")
+            .env("l2t_stream_start")
+            .conf("stream_start")
+            .t_def::<StartPolicy>(StartPolicy::default());
+
+        conf.timeshift_max_size = cfg
+            .grab()
+            .arg("timeshift_max_size")
+            .env("l2t_timeshift_max_size")
+            .conf("timeshift_max_size")
+            .t_def::<u64>(4096);
+
+        conf.timeshift_minutes = cfg
+            .grab()
+            .arg("timeshift_minutes")
+            .env("l2t_timeshift_minutes")
+            .conf("timeshift_minutes")
+            .t_def::<u64>(60);
+
+        // First check if there's a comma-separated list from the command line
+        conf.timeshift_stations = match cfg.grab().arg("timeshift_stations").done() {
+            Some(o) => Some(o.split(',').map(|x| x.to_owned()).collect()),
+            // Otherwise check for a comma-separated list from env variables
+            None => match cfg.grab().env("l2t_timeshift_stations").done() {
+                Some(eo) => Some(eo.split(',').map(|x| x.to_owned()).collect()),
+                // If nothing, get from config
+                None => cfg
+                    .grab_multi()
+                    .conf("timeshift_stations")
+                    .done()
+                    .map(|o| o.collect()),
+            },
+        };
+
+        // Same for UDP outputs, which have to be valid
+        let udp_outputs: Option<Vec<String>> = match cfg.grab().arg("udp_outputs").done() {
+            Some(o) => Some(o.split(',').map(|x| x.to_owned()).collect()),
+            None => match cfg.grab().env("l2t_udp_outputs").done() {
+                Some(eo) => Some(eo.split(',').map(|x| x.to_owned()).collect()),
+                None => cfg
+                    .grab_multi()
+                    .conf("udp_outputs")
+                    .done()
+                    .map(|o| o.collect()),
+            },
+        };
+        conf.udp_outputs = match udp_outputs {
+            Some(outputs) => Some(
+                outputs
+                    .iter()
+                    .map(|o| {
+                        o.parse().map_err(|e| {
+                            SimpleError::new(format!("{} (udp_outputs entry \"{}\")", e, o))
+                        })
+                    })
+                    .collect::<Result<Vec<UdpOutput>, SimpleError>>()?,
+            ),
+            None => None,
+        };
+
         conf.device_model = cfg
             .grab()
             .arg("device_model")
@@ -190,10 +436,20 @@ impl Config {
             .conf("device_version")
             .def("20170612");
 
+        conf.disable_adaptive_bitrate = cfg.bool_flag("disable_adaptive_bitrate", Filter::Arg)
+            || env_true_flag(&cfg, "l2t_disable_adaptive_bitrate")
+            || cfg.bool_flag("disable_adaptive_bitrate", Filter::Conf);
+
         conf.disable_station_cache = cfg.bool_flag("disable_station_cache", Filter::Arg)
             || env_true_flag(&cfg, "l2t_disable_station_cache")
             || cfg.bool_flag("disable_station_cache", Filter::Conf);
 
+        conf.disable_ssdp = cfg.bool_flag("disable_ssdp", Filter::Arg)
+            || env_true_flag(&cfg, "l2t_disable_ssdp")
+            || cfg.bool_flag("disable_ssdp", Filter::Conf);
+
+        conf.reset_device_ids = cfg.bool_flag("reset_device_ids", Filter::Arg);
+
         conf.disable_donation_check = cfg.bool_flag("disable_donation_check", Filter::Arg)
             || env_true_flag(&cfg, "l2t_disable_donation_check")
             || cfg.bool_flag("disable_donation_check", Filter::Conf);
@@ -246,6 +502,56 @@ impl Config {
             || env_true_flag(&cfg, "l2t_skip_hls")
             || cfg.bool_flag("skip_hls", Filter::Conf);
 
+        conf.variant_policy = VariantPolicy {
+            lowest: cfg.bool_flag("variant_lowest", Filter::Arg)
+                || env_true_flag(&cfg, "l2t_variant_lowest")
+                || cfg.bool_flag("variant_lowest", Filter::Conf),
+            max_bandwidth: cfg
+                .grab()
+                .arg("variant_max_bandwidth")
+                .env("l2t_variant_max_bandwidth")
+                .conf("variant_max_bandwidth")
+                .t_done::<u64>(),
+            max_resolution: cfg
+                .grab()
+                .arg("variant_max_resolution")
+                .env("l2t_variant_max_resolution")
+                .conf("variant_max_resolution")
+                .t_done::<usize>(),
+            codec: cfg
+                .grab()
+                .arg("variant_codec")
+                .env("l2t_variant_codec")
+                .conf("variant_codec")
+                .done(),
+            frame_rate: cfg
+                .grab()
+                .arg("variant_frame_rate")
+                .env("l2t_variant_frame_rate")
+                .conf("variant_frame_rate")
+                .t_done::<f32>(),
+        };
+
+        conf.audio_language = cfg
+            .grab()
+            .arg("audio_language")
+            .env("l2t_audio_language")
+            .conf("audio_language")
+            .done();
+
+        conf.ffmpeg = cfg
+            .grab()
+            .arg("ffmpeg")
+            .env("l2t_ffmpeg")
+            .conf("ffmpeg")
+            .done();
+
+        let config_file = clap
+            .value_of("config")
+            .unwrap_or("/etc/locast2tuner/config");
+        conf.profiles = load_table(config_file, "profiles")?;
+        conf.transcode = load_table(config_file, "transcode")?;
+
         let default_cache_dir = dirs::home_dir().unwrap().join(Path::new(".locast2tuner"));
 
         let cache_directory_name = cfg
@@ -267,6 +573,72 @@ impl Config {
 
         Ok(conf)
     }
+
+    /// Return the `VariantPolicy` for a named profile, or the default policy if no profile is
+    /// specified. Besides the profiles from the config file, `lowest` and `highest` are always
+    /// available.
+    pub fn profile(&self, name: Option<&str>) -> Option<VariantPolicy> {
+        match name {
+            None => Some(self.variant_policy.clone()),
+            Some(name) => match self.profiles.get(name) {
+                Some(p) => Some(p.clone()),
+                None if name == "lowest" => Some(VariantPolicy {
+                    lowest: true,
+                    ..Default::default()
+                }),
+                None if name == "highest" => Some(VariantPolicy::default()),
+                None => None,
+            },
+        }
+    }
+
+    /// Return the `TranscodeProfile` with `name`. Besides the profiles from the config file, the
+    /// HDHomeRun EXTEND profiles (`heavy`, `mobile` and `internet240` - `internet720`) are always
+    /// available.
+    pub fn transcode_profile(&self, name: &str) -> Option<TranscodeProfile> {
+        match self.transcode.get(name) {
+            Some(p) => Some(p.clone()),
+            None => BUILTIN_TRANSCODE_PROFILES
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, args)| TranscodeProfile {
+                    args: args.to_string(),
+                }),
+        }
+    }
+
+    /// Names of all transcoding profiles
+    pub fn transcode_profiles(&self) -> Vec<String> {
+        let mut names: Vec<String> = BUILTIN_TRANSCODE_PROFILES
+            .iter()
+            .map(|(n, _)| n.to_string())
+            .chain(self.transcode.keys().cloned())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+// Load the `[<table>.<name>]` tables (e.g. `[profiles.mobile]`) from the config file
+fn load_table<T: DeserializeOwned>(
+    config_file: &str,
+    table: &str,
+) -> Result<HashMap<String, T>, SimpleError> {
+    let contents = match fs::read_to_string(config_file) {
+        Ok(c) => c,
+        Err(_) => return Ok(HashMap::new()),
+    };
+    let value = contents
+        .parse::<Value>()
+        .map_err(|e| SimpleError::new(format!("Unable to parse config file: {}", e)))?;
+    match value.get(table) {
+        Some(t) => t
+            .clone()
+            .try_into()
+            .map_err(|e| SimpleError::new(format!("Invalid {} in config file: {}", table, e))),
+        None => Ok(HashMap::new()),
+    }
 }
 
 // Create the cache directory
@@ -281,7 +653,7 @@ fn create_cache_directory(name: String) -> PathBuf {
 
 // Load the UUID from cache directory if exists
 fn load_uuid(cache_directory: &Path) -> Result<String, Box<dyn std::error::Error>> {
-    let uid_file = cache_directory.join(Path::new("uuid"));
+    let uid_file = cache_directory.join(Path::new(UUID_FILE));
     let uuid = match uid_file.exists() {
         true => fs::read_to_string(uid_file)?,
         false => generate_and_store_uid(uid_file),
@@ -0,0 +1,313 @@
+use super::{local_address, Device};
+use crate::config::Config;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+// Port HDHomeRun devices listen on for discovery requests
+static DISCOVER_PORT: u16 = 65001;
+
+// Packet types
+static DISCOVER_REQUEST: u16 = 0x0002;
+static DISCOVER_REPLY: u16 = 0x0003;
+
+// Tags of the fields in a discovery packet
+static TAG_DEVICE_TYPE: u8 = 0x01;
+static TAG_DEVICE_ID: u8 = 0x02;
+static TAG_TUNER_COUNT: u8 = 0x10;
+static TAG_LINEUP_URL: u8 = 0x27;
+static TAG_BASE_URL: u8 = 0x2a;
+static TAG_DEVICE_AUTH: u8 = 0x2b;
+
+// Device type of a tuner, and the value that matches any device type or ID
+static DEVICE_TYPE_TUNER: u32 = 0x0000_0001;
+static WILDCARD: u32 = 0xffff_ffff;
+
+/// Answer HDHomeRun discovery requests (the binary protocol on UDP port 65001) for `devices`, so
+/// apps that don't use HTTP or SSDP to find tuners see them like HDHomeRun hardware.
+pub fn start(config: &Config, devices: Vec<Device>) {
+    let bind = config.bind_address.parse().unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let tuner_count = config.tuner_count;
+    let socket = match open() {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(
+                "Unable to open HDHomeRun discovery port {}: {}",
+                DISCOVER_PORT, e
+            );
+            return;
+        }
+    };
+    info!(
+        "Answering HDHomeRun discovery requests on port {}",
+        DISCOVER_PORT
+    );
+
+    tokio::spawn(async move {
+        let mut buffer = [0; 1460];
+        loop {
+            let (size, peer) = match socket.recv_from(&mut buffer).await {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Unable to receive HDHomeRun discovery request: {}", e);
+                    continue;
+                }
+            };
+            let (device_type, device_id) = match parse_request(&buffer[..size]) {
+                Some(r) => r,
+                None => continue,
+            };
+            if device_type != DEVICE_TYPE_TUNER && device_type != WILDCARD {
+                continue;
+            }
+            let address = match local_address(bind, peer) {
+                Some(a) => a,
+                None => continue,
+            };
+            debug!("HDHomeRun discovery request from {}", peer);
+
+            for device in devices
+                .iter()
+                .filter(|d| device_id == WILDCARD || device_id == d.device_id)
+            {
+                let base_url = format!("http://{}:{}", address, device.port);
+                let reply = reply(device, tuner_count, &base_url);
+                if let Err(e) = socket.send_to(&reply, peer).await {
+                    warn!("Unable to answer HDHomeRun discovery request: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Open the discovery port. Requests are broadcast, so the socket is bound to all interfaces.
+fn open() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVER_PORT)).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Get the requested device type and device ID from a discovery request. Fields that are missing
+/// match anything. Returns `None` if the packet isn't a valid discovery request.
+fn parse_request(packet: &[u8]) -> Option<(u32, u32)> {
+    let packet_type = u16::from_be_bytes([*packet.first()?, *packet.get(1)?]);
+    let length = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize;
+    let crc = packet.get(4 + length..8 + length)?;
+    if packet_type != DISCOVER_REQUEST || crc32(&packet[..4 + length]).to_le_bytes() != crc[..] {
+        return None;
+    }
+
+    let mut device_type = WILDCARD;
+    let mut device_id = WILDCARD;
+    let mut payload = &packet[4..4 + length];
+    while !payload.is_empty() {
+        let tag = payload[0];
+        let (length, header) = match *payload.get(1)? {
+            l if l & 0x80 == 0 => (l as usize, 2),
+            l => ((l as usize & 0x7f) | ((*payload.get(2)? as usize) << 7), 3),
+        };
+        let value = payload.get(header..header + length)?;
+        if value.len() == 4 {
+            let value = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+            if tag == TAG_DEVICE_TYPE {
+                device_type = value;
+            } else if tag == TAG_DEVICE_ID {
+                device_id = value;
+            }
+        }
+        payload = &payload[header + length..];
+    }
+    Some((device_type, device_id))
+}
+
+/// Build the discovery reply for `device`
+fn reply(device: &Device, tuner_count: u8, base_url: &str) -> Vec<u8> {
+    let mut payload = Vec::new();
+    field(
+        &mut payload,
+        TAG_DEVICE_TYPE,
+        &DEVICE_TYPE_TUNER.to_be_bytes(),
+    );
+    field(&mut payload, TAG_DEVICE_ID, &device.device_id.to_be_bytes());
+    field(&mut payload, TAG_TUNER_COUNT, &[tuner_count]);
+    field(&mut payload, TAG_BASE_URL, base_url.as_bytes());
+    field(
+        &mut payload,
+        TAG_LINEUP_URL,
+        format!("{}/lineup.json", base_url).as_bytes(),
+    );
+    field(&mut payload, TAG_DEVICE_AUTH, b"locast2dvr");
+
+    let mut packet = Vec::with_capacity(payload.len() + 8);
+    packet.extend_from_slice(&DISCOVER_REPLY.to_be_bytes());
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&payload);
+    let crc = crc32(&packet);
+    packet.extend_from_slice(&crc.to_le_bytes());
+    packet
+}
+
+/// Append a tag-length-value field. Lengths over 127 take two bytes.
+fn field(payload: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    payload.push(tag);
+    if value.len() > 127 {
+        payload.push((value.len() & 0x7f) as u8 | 0x80);
+        payload.push((value.len() >> 7) as u8);
+    } else {
+        payload.push(value.len() as u8);
+    }
+    payload.extend_from_slice(value);
+}
+
+/// The CRC-32 (as used by Ethernet) that ends every packet
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{hdhr_checksum, hdhr_valid_device_id};
+
+    /// A discovery request for any tuner, as sent by `hdhomerun_config discover`
+    static CAPTURED_REQUEST: [u8; 20] = [
+        0x00, 0x02, 0x00, 0x0c, 0x01, 0x04, 0x00, 0x00, 0x00, 0x01, 0x02, 0x04, 0xff, 0xff, 0xff,
+        0xff, 0x4e, 0x50, 0x7f, 0x35,
+    ];
+
+    /// Build a packet of `packet_type` around `payload`
+    fn packet(packet_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = packet_type.to_be_bytes().to_vec();
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(payload);
+        let crc = crc32(&packet);
+        packet.extend_from_slice(&crc.to_le_bytes());
+        packet
+    }
+
+    /// Split the payload of a packet into tag-value pairs
+    fn fields(mut payload: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut fields = Vec::new();
+        while !payload.is_empty() {
+            let (length, header) = if payload[1] & 0x80 == 0 {
+                (payload[1] as usize, 2)
+            } else {
+                ((payload[1] as usize & 0x7f) | (payload[2] as usize) << 7, 3)
+            };
+            fields.push((payload[0], payload[header..header + length].to_vec()));
+            payload = &payload[header + length..];
+        }
+        fields
+    }
+
+    #[test]
+    fn crc32_of_captured_packet() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(&CAPTURED_REQUEST[..16]).to_le_bytes(),
+            CAPTURED_REQUEST[16..]
+        );
+    }
+
+    #[test]
+    fn parse_captured_request() {
+        assert_eq!(
+            parse_request(&CAPTURED_REQUEST),
+            Some((DEVICE_TYPE_TUNER, WILDCARD))
+        );
+    }
+
+    #[test]
+    fn parse_request_for_device() {
+        let request = packet(
+            DISCOVER_REQUEST,
+            &[
+                0x01, 0x04, 0xff, 0xff, 0xff, 0xff, 0x02, 0x04, 0x10, 0x5a, 0x3c, 0x2b,
+            ],
+        );
+        assert_eq!(parse_request(&request), Some((WILDCARD, 0x105a_3c2b)));
+        // Without fields, anything matches
+        assert_eq!(
+            parse_request(&packet(DISCOVER_REQUEST, &[])),
+            Some((WILDCARD, WILDCARD))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_requests() {
+        let mut bad_crc = CAPTURED_REQUEST;
+        bad_crc[19] ^= 0xff;
+        assert_eq!(parse_request(&bad_crc), None);
+        assert_eq!(parse_request(&CAPTURED_REQUEST[..18]), None);
+        assert_eq!(parse_request(&[]), None);
+        // Replies are not requests
+        assert_eq!(
+            parse_request(&packet(DISCOVER_REPLY, &CAPTURED_REQUEST[4..16])),
+            None
+        );
+        // A field that runs past the end of the payload
+        assert_eq!(
+            parse_request(&packet(DISCOVER_REQUEST, &[0x01, 0x04, 0x00])),
+            None
+        );
+    }
+
+    #[test]
+    fn build_reply() {
+        let device = Device {
+            uuid: "3b1c0f2e-0000-0000-0000-000000000000".to_owned(),
+            device_id: hdhr_valid_device_id(0x3b1c_0f2e),
+            port: 6077,
+        };
+        let reply = reply(&device, 4, "http://192.168.1.10:6077");
+
+        assert_eq!(reply[..2], DISCOVER_REPLY.to_be_bytes());
+        let length = u16::from_be_bytes([reply[2], reply[3]]) as usize;
+        assert_eq!(reply.len(), 4 + length + 4);
+        assert_eq!(
+            crc32(&reply[..4 + length]).to_le_bytes(),
+            reply[4 + length..]
+        );
+
+        let fields = fields(&reply[4..4 + length]);
+        assert_eq!(
+            fields,
+            vec![
+                (TAG_DEVICE_TYPE, vec![0, 0, 0, 1]),
+                (TAG_DEVICE_ID, device.device_id.to_be_bytes().to_vec()),
+                (TAG_TUNER_COUNT, vec![4]),
+                (TAG_BASE_URL, b"http://192.168.1.10:6077".to_vec()),
+                (
+                    TAG_LINEUP_URL,
+                    b"http://192.168.1.10:6077/lineup.json".to_vec()
+                ),
+                (TAG_DEVICE_AUTH, b"locast2dvr".to_vec()),
+            ]
+        );
+        // HDHomeRun apps ignore devices with an invalid device ID
+        assert_eq!(hdhr_checksum(device.device_id as usize), 0);
+    }
+
+    #[test]
+    fn long_field() {
+        let mut payload = Vec::new();
+        field(&mut payload, TAG_BASE_URL, &[b'x'; 300]);
+        assert_eq!(payload[..3], [TAG_BASE_URL, 0x80 | (300 & 0x7f) as u8, 2]);
+        assert_eq!(payload.len(), 303);
+        assert_eq!(fields(&payload), vec![(TAG_BASE_URL, vec![b'x'; 300])]);
+    }
+}
@@ -0,0 +1,80 @@
+pub mod hdhomerun;
+pub mod ssdp;
+use crate::{
+    config::UUID_FILE,
+    utils::{hdhr_valid_device_id, load_json, save_json},
+};
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+};
+
+// File in the cache directory that holds the device IDs of all tuners
+static DEVICE_IDS_FILE: &str = "device_ids";
+
+/// A tuner that is announced on the network. Every HTTP server that is started is a tuner.
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub uuid: String,
+    /// Device ID as used by the HDHomeRun discovery protocol
+    pub device_id: u32,
+    pub port: u16,
+}
+
+/// The local address that `peer` can reach us on. If the HTTP servers are bound to a specific
+/// address, that's the one. Otherwise the address of the interface that traffic to `peer` goes out
+/// of is used.
+fn local_address(bind: Ipv4Addr, peer: SocketAddr) -> Option<IpAddr> {
+    if !bind.is_unspecified() {
+        return Some(IpAddr::V4(bind));
+    }
+    // Connecting a UDP socket doesn't send anything, it only picks a route
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(peer).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// HDHomeRun device IDs of tuners by UUID. Every tuner gets its own device ID, which is stored in
+/// the cache directory, so media servers keep recognizing a tuner after a restart.
+pub struct DeviceIds {
+    path: PathBuf,
+    ids: HashMap<String, u32>,
+}
+
+impl DeviceIds {
+    /// Load the device IDs that were handed out before
+    pub fn load(cache_directory: &Path) -> DeviceIds {
+        let path = cache_directory.join(DEVICE_IDS_FILE);
+        let ids = load_json(&path, "device IDs");
+        DeviceIds { path, ids }
+    }
+
+    /// Get the device ID of the tuner with `uuid`. A new device ID is derived from the UUID and
+    /// stored, unless another tuner already has it.
+    pub fn device_id(&mut self, uuid: &str) -> u32 {
+        if let Some(id) = self.ids.get(uuid) {
+            return *id;
+        }
+        let mut id = hdhr_valid_device_id(u32::from_str_radix(&uuid[..8], 16).unwrap());
+        while self.ids.values().any(|i| *i == id) {
+            id = hdhr_valid_device_id(id.wrapping_add(0x10));
+        }
+        self.ids.insert(uuid.to_owned(), id);
+        save_json(&self.path, &self.ids, "device IDs");
+        id
+    }
+}
+
+/// Forget the UUID and device IDs of all tuners. New ones are generated on the next start, so media
+/// servers see brand new tuners.
+pub fn reset(cache_directory: &Path) -> std::io::Result<()> {
+    for file in &[UUID_FILE, DEVICE_IDS_FILE] {
+        let path = cache_directory.join(file);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
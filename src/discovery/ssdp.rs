@@ -0,0 +1,266 @@
+use super::{local_address, Device};
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    net::UdpSocket,
+    task::JoinHandle,
+    time::{sleep, Duration},
+};
+
+static SSDP_ADDRESS: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+static SSDP_PORT: u16 = 1900;
+
+// How long (in seconds) clients may consider an announcement valid
+static MAX_AGE: u64 = 1800;
+
+// How often (in seconds) tuners are announced. This is well within `MAX_AGE`, so an announcement
+// that gets lost doesn't make tuners disappear.
+static NOTIFY_INTERVAL: u64 = 600;
+
+// Maximum number of seconds to wait before answering a search, even if the client allows more
+static MAX_DELAY: u64 = 3;
+
+// Device type of the root device, as described in device.xml
+static DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+
+/// Announces tuners on the network through SSDP and answers SSDP searches, so media servers can
+/// find them without having to enter an address.
+pub struct Ssdp {
+    socket: Arc<UdpSocket>,
+    bind: Ipv4Addr,
+    devices: Arc<Vec<Device>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Ssdp {
+    /// Start announcing `devices` and answering searches for them. Multicast is received and sent
+    /// on the interface of `bind_address`. Returns `None` if the SSDP port can't be opened.
+    pub fn start(bind_address: &str, devices: Vec<Device>) -> Option<Ssdp> {
+        let bind = match bind_address.parse::<Ipv4Addr>() {
+            Ok(b) => b,
+            Err(_) => {
+                warn!(
+                    "SSDP needs an IPv4 bind address, not {}. Disabling SSDP..",
+                    bind_address
+                );
+                return None;
+            }
+        };
+        let socket = match open(bind) {
+            Ok(s) => Arc::new(s),
+            Err(e) => {
+                warn!("Unable to open SSDP port {}: {}", SSDP_PORT, e);
+                return None;
+            }
+        };
+        let devices = Arc::new(devices);
+
+        let listener = {
+            let socket = socket.clone();
+            let devices = devices.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0; 2048];
+                loop {
+                    let (size, peer) = match socket.recv_from(&mut buffer).await {
+                        Ok(r) => r,
+                        Err(e) => {
+                            warn!("Unable to receive SSDP message: {}", e);
+                            continue;
+                        }
+                    };
+                    let request = String::from_utf8_lossy(&buffer[..size]);
+                    if let Some((target, mx)) = search(&request) {
+                        debug!("SSDP search from {} for {}", peer, target);
+                        answer(socket.clone(), bind, devices.clone(), peer, target, mx);
+                    }
+                }
+            })
+        };
+
+        let announcer = {
+            let socket = socket.clone();
+            let devices = devices.clone();
+            tokio::spawn(async move {
+                loop {
+                    notify(&socket, bind, &devices, true).await;
+                    sleep(Duration::from_secs(NOTIFY_INTERVAL)).await;
+                }
+            })
+        };
+
+        info!(
+            "Announcing {} tuner(s) through SSDP on {}",
+            devices.len(),
+            bind
+        );
+        Some(Ssdp {
+            socket,
+            bind,
+            devices,
+            tasks: vec![listener, announcer],
+        })
+    }
+
+    /// Stop answering searches and tell clients the tuners are gone
+    pub async fn stop(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        notify(&self.socket, self.bind, &self.devices, false).await;
+    }
+}
+
+/// Open the SSDP port and join the SSDP multicast group. Other programs on the same host may use
+/// SSDP as well, so the port is shared.
+fn open(bind: Ipv4Addr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, SSDP_PORT)).into())?;
+    socket.join_multicast_v4(&SSDP_ADDRESS, &bind)?;
+    if !bind.is_unspecified() {
+        socket.set_multicast_if_v4(&bind)?;
+    }
+    // UPnP recommends a TTL of 2
+    socket.set_multicast_ttl_v4(2)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Get the search target and maximum wait time (`MX`) of an `M-SEARCH` request
+fn search(request: &str) -> Option<(String, u64)> {
+    let mut lines = request.lines();
+    if !lines.next()?.starts_with("M-SEARCH") {
+        return None;
+    }
+    let mut target = None;
+    let mut mx = 1;
+    for (name, value) in lines.filter_map(|l| l.split_once(':')) {
+        match name.trim().to_uppercase().as_str() {
+            "ST" => target = Some(value.trim().to_owned()),
+            "MX" => mx = value.trim().parse().unwrap_or(1),
+            _ => {}
+        }
+    }
+    Some((target?, mx))
+}
+
+/// The notification types (`NT`/`ST`) and unique service names (`USN`) of a device
+fn targets(device: &Device) -> Vec<(String, String)> {
+    let uuid = format!("uuid:{}", device.uuid);
+    vec![
+        (
+            "upnp:rootdevice".to_owned(),
+            format!("{}::upnp:rootdevice", uuid),
+        ),
+        (uuid.to_owned(), uuid.to_owned()),
+        (DEVICE_TYPE.to_owned(), format!("{}::{}", uuid, DEVICE_TYPE)),
+    ]
+}
+
+/// Answer a search for `target` from `peer`. Answers are sent after a random delay of up to `mx`
+/// seconds, so clients don't get flooded when many devices answer at once.
+fn answer(
+    socket: Arc<UdpSocket>,
+    bind: Ipv4Addr,
+    devices: Arc<Vec<Device>>,
+    peer: SocketAddr,
+    target: String,
+    mx: u64,
+) {
+    let address = match local_address(bind, peer) {
+        Some(a) => a,
+        None => return,
+    };
+    let delay = rand::thread_rng().gen_range(0..=mx.min(MAX_DELAY) * 1000);
+
+    tokio::spawn(async move {
+        sleep(Duration::from_millis(delay)).await;
+        for device in devices.iter() {
+            for (nt, usn) in targets(device) {
+                if target != "ssdp:all" && !target.eq_ignore_ascii_case(&nt) {
+                    continue;
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     CACHE-CONTROL: max-age={}\r\n\
+                     EXT:\r\n\
+                     LOCATION: http://{}:{}/device.xml\r\n\
+                     SERVER: {}\r\n\
+                     ST: {}\r\n\
+                     USN: {}\r\n\r\n",
+                    MAX_AGE,
+                    address,
+                    device.port,
+                    server(),
+                    nt,
+                    usn
+                );
+                if let Err(e) = socket.send_to(response.as_bytes(), peer).await {
+                    warn!("Unable to answer SSDP search from {}: {}", peer, e);
+                }
+            }
+        }
+    });
+}
+
+/// Announce that `devices` are available (`alive`) or are going away
+async fn notify(socket: &UdpSocket, bind: Ipv4Addr, devices: &[Device], alive: bool) {
+    let group = SocketAddr::from((SSDP_ADDRESS, SSDP_PORT));
+    let address = match local_address(bind, group) {
+        Some(a) => a,
+        None => {
+            warn!("Unable to determine the address to announce through SSDP");
+            return;
+        }
+    };
+
+    for device in devices {
+        for (nt, usn) in targets(device) {
+            let message = if alive {
+                format!(
+                    "NOTIFY * HTTP/1.1\r\n\
+                     HOST: {}\r\n\
+                     CACHE-CONTROL: max-age={}\r\n\
+                     LOCATION: http://{}:{}/device.xml\r\n\
+                     NT: {}\r\n\
+                     NTS: ssdp:alive\r\n\
+                     SERVER: {}\r\n\
+                     USN: {}\r\n\r\n",
+                    group,
+                    MAX_AGE,
+                    address,
+                    device.port,
+                    nt,
+                    server(),
+                    usn
+                )
+            } else {
+                format!(
+                    "NOTIFY * HTTP/1.1\r\n\
+                     HOST: {}\r\n\
+                     NT: {}\r\n\
+                     NTS: ssdp:byebye\r\n\
+                     USN: {}\r\n\r\n",
+                    group, nt, usn
+                )
+            };
+            if let Err(e) = socket.send_to(message.as_bytes(), group).await {
+                warn!("Unable to send SSDP announcement: {}", e);
+            }
+        }
+    }
+}
+
+/// The `SERVER` header: OS, UPnP version and product
+fn server() -> String {
+    format!(
+        "{}/{} UPnP/1.1 locast2tuner/{}",
+        sys_info::os_type().unwrap_or_default(),
+        sys_info::os_release().unwrap_or_default(),
+        env!("CARGO_PKG_VERSION")
+    )
+}
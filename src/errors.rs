@@ -5,18 +5,34 @@ use derive_more::{Display, Error};
 pub enum AppError {
     #[display(fmt = "not found")]
     NotFound,
+    #[display(fmt = "bad request")]
+    BadRequest,
+    #[display(fmt = "bad gateway")]
+    BadGateway,
+    #[display(fmt = "All tuners busy")]
+    AllTunersBusy,
+    #[display(fmt = "internal server error")]
+    InternalServerError,
 }
 
 impl error::ResponseError for AppError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponseBuilder::new(self.status_code())
-            .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
-            .body(self.to_string())
+        let mut builder = HttpResponseBuilder::new(self.status_code());
+        builder.insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"));
+        // This is what an HDHomeRun responds with when all tuners are in use
+        if let AppError::AllTunersBusy = self {
+            builder.insert_header(("X-HDHomeRun-Error", "805 All Tuners In Use"));
+        }
+        builder.body(self.to_string())
     }
 
     fn status_code(&self) -> StatusCode {
         match *self {
             AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::BadRequest => StatusCode::BAD_REQUEST,
+            AppError::BadGateway => StatusCode::BAD_GATEWAY,
+            AppError::AllTunersBusy => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
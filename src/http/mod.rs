@@ -1,33 +1,71 @@
 mod templates;
 use crate::{
-    config::Config,
-    service::{station::ChannelRemapEntry, station_provider::StationProvider},
-    utils::Or,
+    config::{Config, StartPolicy, TranscodeProfile, VariantPolicy},
+    discovery::{hdhomerun, ssdp::Ssdp, Device, DeviceIds},
+    errors::AppError,
+    service::{
+        favorites::Favorites,
+        scan::ScanProgress,
+        station::{ChannelRemapEntry, Listing, Station},
+        station_provider::StationProvider,
+        variant::{audio_rendition, select_variant},
+    },
+    streaming::{
+        hub::Hubs,
+        limit_duration,
+        passthrough::{master_playlist, HlsProxy, Track},
+        sessions::Sessions,
+        subtitles::subtitles,
+        timeshift::TimeShift,
+        transcode::transcode,
+        tuners::Tuners,
+        udp,
+    },
+    utils::{quality, Or},
 };
 use actix_web::middleware::Logger;
 use actix_web::{dev::Server, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web::{middleware::Compat, Error};
 use actix_web::{middleware::Condition, ResponseError};
-use chrono::{DateTime, Utc};
-use futures::{future, lock::Mutex, stream, Stream};
+use chrono::Utc;
+use futures::{future, lock::Mutex, StreamExt};
 use log::info;
 use prettytable::{cell, format, row, Table};
-use reqwest::{header::LOCATION, Url};
+use reqwest::{
+    header::{LOCATION, USER_AGENT},
+    Url,
+};
 use serde::Serialize;
 use std::collections::HashMap;
-use std::str::FromStr;
-use std::{collections::VecDeque, sync::Arc};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use string_builder::Builder;
-use uuid::Uuid;
+use tokio::task::JoinHandle;
 
 const NETWORKS: [&str; 6] = ["ABC", "CBS", "NBC", "FOX", "CW", "PBS"];
 
+// Content type of streams whose codecs haven't been detected yet
+const DEFAULT_CONTENT_TYPE: &str = "video/mpeg; codecs='avc1.4D401E'";
+
 /// Struct that is passed to HTTP handlers that contains config, the service that can be used to
 /// lookup locast data, etc.
 struct AppState<T: StationProvider> {
     config: Arc<Config>,
     service: T,
-    station_scan: Mutex<bool>,
+    device_id: u32,
+    station_scan: Mutex<Option<Scan>>,
+    hubs: Arc<Hubs>,
+    hls: HlsProxy,
+    tuners: Arc<Tuners>,
+    sessions: Arc<Sessions>,
+    timeshift: Arc<TimeShift>,
+    favorites: Arc<Favorites>,
+}
+
+/// A channel scan that was started through `lineup.post`
+struct Scan {
+    progress: Arc<ScanProgress>,
+    task: JoinHandle<()>,
 }
 
 /// Start the HTTP server that will handle media server requests
@@ -36,6 +74,17 @@ pub async fn start<T: 'static + StationProvider + Sync + Send + Clone>(
     config: Arc<Config>,
 ) -> std::io::Result<()> {
     let reporting_services = services.clone();
+    // Tuners are shared by all servers, so they can be counted globally
+    let tuners = Arc::new(Tuners::new(config.clone()));
+    let sessions = Arc::new(Sessions::new());
+    let timeshift = Arc::new(TimeShift::new(config.clone()));
+    let favorites = Arc::new(Favorites::load(&config.cache_directory));
+    // Every server is a separate HDHomeRun device with its own device ID
+    let mut device_ids = DeviceIds::load(&config.cache_directory);
+    let device_ids: Vec<u32> = services
+        .iter()
+        .map(|s| device_ids.device_id(&s.uuid()))
+        .collect();
     // Start a server for each service that is passed in
     let servers: Vec<Server> = services
         .into_iter()
@@ -51,11 +100,25 @@ pub async fn start<T: 'static + StationProvider + Sync + Send + Clone>(
                 port
             );
 
+            // Start recording time-shifted stations
+            let hubs = Arc::new(Hubs::new(config.clone()));
+            timeshift.record(hubs.clone(), service.clone());
+
+            // Push stations to UDP outputs
+            udp::start(config.clone(), hubs.clone(), service.clone());
+
             // Construct some app_state we can pass around
             let app_state = web::Data::new(AppState::<T> {
                 config: config.clone(),
                 service,
-                station_scan: Mutex::new(false),
+                device_id: device_ids[i],
+                station_scan: Mutex::new(None),
+                hubs,
+                hls: HlsProxy::new(),
+                tuners: tuners.clone(),
+                sessions: sessions.clone(),
+                timeshift: timeshift.clone(),
+                favorites: favorites.clone(),
             });
 
             let verbose = config.verbose;
@@ -66,6 +129,7 @@ pub async fn start<T: 'static + StationProvider + Sync + Send + Clone>(
                     .wrap(Condition::new(verbose > 0, Compat::new(Logger::default())))
                     .app_data(app_state.clone())
                     .route("/", web::get().to(device_xml::<T>))
+                    .route("/auto/v{guide_number}", web::get().to(watch_auto::<T>))
                     .route("/config", web::get().to(show_config::<T>))
                     .route("/device.xml", web::get().to(device_xml::<T>))
                     .route("/discover.json", web::get().to(discover::<T>))
@@ -73,12 +137,38 @@ pub async fn start<T: 'static + StationProvider + Sync + Send + Clone>(
                     .route("/epg", web::get().to(epg::<T>))
                     .route("/lineup_status.json", web::get().to(lineup_status::<T>))
                     .route("/lineup.json", web::get().to(lineup_json::<T>))
-                    .route("/lineup.post", web::post().to(lineup_post))
+                    .route("/lineup.post", web::post().to(lineup_post::<T>))
                     .route("/lineup.xml", web::get().to(lineup_xml::<T>))
                     .route("/remap", web::get().to(map_json::<T>))
+                    .route("/streams", web::get().to(streams::<T>))
+                    .route("/streams/{id}", web::delete().to(kill_stream::<T>))
                     .route("/map.json", web::get().to(map_json::<T>))
                     .route("/tuner.m3u", web::get().to(tuner_m3u::<T>))
                     .service(web::resource("/watch/{id}.m3u").route(web::get().to(watch_m3u::<T>)))
+                    .service(
+                        web::resource("/watch/{id}/index.m3u8")
+                            .route(web::get().to(watch_hls::<T>)),
+                    )
+                    .service(
+                        web::resource("/watch/{id}/master.m3u8")
+                            .route(web::get().to(watch_hls_master::<T>)),
+                    )
+                    .service(
+                        web::resource("/watch/{id}/subtitles.vtt")
+                            .route(web::get().to(watch_subtitles::<T>)),
+                    )
+                    .service(
+                        web::resource("/watch/{id}/{segment}.ts")
+                            .route(web::get().to(watch_hls_segment::<T>)),
+                    )
+                    .service(
+                        web::resource("/watch/{id}/{segment}.vtt")
+                            .route(web::get().to(watch_hls_segment::<T>)),
+                    )
+                    .service(
+                        web::resource("/watch/{id}/{segment}.key")
+                            .route(web::get().to(watch_hls_segment::<T>)),
+                    )
                     .service(web::resource("/watch/{id}").route(web::get().to(watch::<T>)))
             })
             .bind((bind_address.to_owned(), port))
@@ -144,8 +234,29 @@ pub async fn start<T: 'static + StationProvider + Sync + Send + Clone>(
         }
     }
 
+    // Announce every server as a tuner, so media servers and apps can find them
+    let devices: Vec<Device> = reporting_services
+        .iter()
+        .enumerate()
+        .map(|(i, s)| Device {
+            uuid: s.uuid(),
+            device_id: device_ids[i],
+            port: config.port + i as u16,
+        })
+        .collect();
+    hdhomerun::start(&config, devices.clone());
+    let ssdp = if config.disable_ssdp {
+        None
+    } else {
+        Ssdp::start(&config.bind_address, devices)
+    };
+
     info!("locast2tuner started..");
-    future::try_join_all(servers).await?;
+    let result = future::try_join_all(servers).await;
+    if let Some(ssdp) = ssdp {
+        ssdp.stop().await;
+    }
+    result?;
     Ok(())
 }
 
@@ -185,15 +296,14 @@ struct DiscoverData {
     DeviceAuth: String,
     BaseURL: String,
     LineupURL: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    TranscodeProfiles: Option<Vec<String>>,
 }
 
 async fn discover<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
     let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
     let host = req.connection_info().host().to_string();
-    let uuid = &data.config.uuid;
-    let device_id = usize::from_str_radix(&uuid[..8], 16).unwrap();
-    let checksum = crate::utils::hdhr_checksum(device_id); // TODO: FIX!
-    let valid_id = format!("{:x}", checksum + device_id);
+    let valid_id = format!("{:08X}", data.device_id);
     let response = DiscoverData {
         FriendlyName: data.service.geo().name.clone(),
         Manufacturer: "locast2dvr".to_string(),
@@ -205,6 +315,11 @@ async fn discover<T: 'static + StationProvider>(req: HttpRequest) -> HttpRespons
         DeviceAuth: "locast2dvr".to_string(),
         BaseURL: format!("http://{}", host),
         LineupURL: format!("http://{}/lineup.json", host),
+        TranscodeProfiles: data
+            .config
+            .ffmpeg
+            .as_ref()
+            .map(|_| data.config.transcode_profiles()),
     };
 
     HttpResponse::Ok().json(&response)
@@ -215,24 +330,27 @@ async fn discover<T: 'static + StationProvider>(req: HttpRequest) -> HttpRespons
 struct LineupStatus {
     ScanInProgress: bool,
     Progress: u8,
-    Found: u8,
+    Found: usize,
     SourceList: Option<Vec<String>>,
 }
 async fn lineup_status<T: StationProvider>(data: web::Data<AppState<T>>) -> impl Responder {
     let station_scan = data.station_scan.lock().await;
-    let response = if *station_scan {
-        LineupStatus {
+    let response = match station_scan.as_ref() {
+        Some(scan) if !scan.progress.finished() => LineupStatus {
             ScanInProgress: true,
-            Progress: 50,
-            Found: 6,
+            Progress: scan.progress.progress(),
+            Found: scan.progress.found(),
             SourceList: None,
-        }
-    } else {
-        LineupStatus {
-            ScanInProgress: false,
-            Progress: 50,
-            Found: 6,
-            SourceList: Some(vec!["Antenna".to_string()]),
+        },
+        _ => {
+            let stations = data.service.stations().await;
+            let found = stations.lock().await.iter().filter(|s| s.active).count();
+            LineupStatus {
+                ScanInProgress: false,
+                Progress: 100,
+                Found: found,
+                SourceList: Some(vec!["Antenna".to_string()]),
+            }
         }
     };
     HttpResponse::Ok().json(&response)
@@ -307,21 +425,85 @@ struct LineupJson {
     GuideNumber: String,
     GuideName: String,
     URL: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    AudioLanguages: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    VideoCodec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    AudioCodec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    HD: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    Favorite: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    DRM: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    Tags: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    Modulation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    StationID: Option<String>,
 }
 
+/// The channels of the tuner. Like on an HDHomeRun, `show` picks which channels are listed:
+/// `found` (the default) lists all active stations, `favorites` only the favorite ones and `demo`
+/// every station, including the ones that were deactivated in the remap file. With `tuning`, the
+/// details needed to tune a station are added.
 async fn lineup_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
     let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
     let host = req.connection_info().host().to_string();
+    let query = match query_parameters(&req) {
+        Ok(q) => q,
+        Err(e) => return e.error_response(),
+    };
+    let tuning = query.contains_key("tuning");
+    let show = query.get("show").map(|s| s.as_str()).unwrap_or("found");
+    if !["found", "favorites", "demo"].contains(&show) {
+        return AppError::BadRequest.error_response();
+    }
+
     let stations_mutex = data.service.stations();
     let stations = stations_mutex.await;
+    let now = Utc::now().timestamp_millis();
 
     let lineup: Vec<LineupJson> = stations
         .lock()
         .await
         .iter()
-        .filter(|s| s.active)
-        .map(|station| {
+        .filter(|s| s.active || show == "demo")
+        .map(|station| (station, data.favorites.contains(&station.id.to_string())))
+        .filter(|(_, favorite)| *favorite || show != "favorites")
+        .map(|(station, favorite)| {
             let url = format!("http://{}/watch/{}", &host, &station.id);
+            let video = station.media_info.as_ref().and_then(|m| m.video.as_ref());
+            let audio = station.media_info.as_ref().and_then(|m| m.audio.as_ref());
+            let listing = current_listing(station, now);
+            // Until the stream has been probed, the listing tells whether a station is in HD
+            let hd = match video {
+                Some(v) => v.is_hd(),
+                None => {
+                    listing
+                        .and_then(|l| l.videoProperties.as_deref())
+                        .map(quality)
+                        .as_deref()
+                        == Some("HDTV")
+                }
+            };
+
+            let mut tags = Vec::new();
+            if favorite {
+                tags.push("favorite".to_owned());
+            }
+            if hd {
+                tags.push("hd".to_owned());
+            }
+            if !station.active {
+                tags.push("hidden".to_owned());
+            }
+            if let Some(genres) = listing.and_then(|l| l.genres.as_ref()) {
+                tags.extend(genres.split(',').map(|g| g.trim().to_lowercase()));
+            }
+
             LineupJson {
                 GuideNumber: station
                     .channel_remapped
@@ -330,6 +512,17 @@ async fn lineup_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpResp
                     .to_owned(),
                 GuideName: station.name.to_owned(),
                 URL: url,
+                AudioLanguages: station.audio_languages.to_owned(),
+                VideoCodec: video.map(|v| v.codec.name().to_owned()),
+                AudioCodec: audio.map(|a| a.codec.name().to_owned()),
+                HD: Some(1).filter(|_| hd),
+                Favorite: Some(1).filter(|_| favorite),
+                // Locast streams are never copy protected
+                DRM: None,
+                Tags: Some(tags.join(",")).filter(|t| !t.is_empty()),
+                // All locast stations are ATSC broadcasts
+                Modulation: Some("8vsb".to_owned()).filter(|_| tuning),
+                StationID: Some(station.id.to_string()).filter(|_| tuning),
             }
         })
         .collect();
@@ -337,6 +530,14 @@ async fn lineup_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpResp
     HttpResponse::Ok().json(lineup)
 }
 
+/// The listing of `station` that airs at `now` (in milliseconds)
+fn current_listing(station: &Station, now: i64) -> Option<&Listing> {
+    station
+        .listings
+        .iter()
+        .find(|l| l.startTime <= now && now < l.startTime + l.duration * 1000)
+}
+
 async fn map_json<T: 'static + StationProvider>(req: HttpRequest) -> HttpResponse {
     let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
     let stations_mutex = data.service.stations();
@@ -392,10 +593,188 @@ async fn epg<T: StationProvider>(data: web::Data<AppState<T>>) -> impl Responder
     HttpResponse::Ok().json(stations)
 }
 
+/// The query parameters of `req`. Handlers parse them once and pass them to the helpers below.
+fn query_parameters(req: &HttpRequest) -> Result<HashMap<String, String>, AppError> {
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .map_err(|_| AppError::BadRequest)
+}
+
+/// Get the `profile` query parameter and the `VariantPolicy` that goes with it. Without a
+/// `profile` parameter the default policy is used.
+fn variant_profile(
+    query: &HashMap<String, String>,
+    config: &Config,
+) -> Result<(Option<String>, VariantPolicy), AppError> {
+    let profile = query.get("profile").cloned();
+    match config.profile(profile.as_deref()) {
+        Some(policy) => Ok((profile, policy)),
+        None => {
+            warn!("Unknown profile: {}", profile.unwrap());
+            Err(AppError::BadRequest)
+        }
+    }
+}
+
+/// Get the `offset` query parameter, which is the number of seconds in the past playback should
+/// start. Both `-900` and `900` mean 15 minutes ago. An offset of 0 means live.
+fn timeshift_offset(query: &HashMap<String, String>) -> Result<Option<u64>, AppError> {
+    match query.get("offset").map(|o| o.parse::<i64>()) {
+        None | Some(Ok(0)) => Ok(None),
+        Some(Ok(o)) => Ok(Some(o.unsigned_abs())),
+        Some(Err(_)) => Err(AppError::BadRequest),
+    }
+}
+
+/// Get the `duration` query parameter, which is the number of seconds of video after which the
+/// stream ends. A duration of 0 means the stream doesn't end.
+fn stream_duration(query: &HashMap<String, String>) -> Result<Option<u64>, AppError> {
+    match query.get("duration").map(|d| d.parse::<u64>()) {
+        None | Some(Ok(0)) => Ok(None),
+        Some(Ok(d)) => Ok(Some(d)),
+        Some(Err(_)) => Err(AppError::BadRequest),
+    }
+}
+
+/// Get the `start` query parameter, which determines where a new stream starts in the playlist.
+/// Without a `start` parameter, `stream_start` from the config is used.
+fn stream_start(query: &HashMap<String, String>, config: &Config) -> Result<StartPolicy, AppError> {
+    match query.get("start").map(|s| s.parse::<StartPolicy>()) {
+        None => Ok(config.stream_start),
+        Some(Ok(start)) => Ok(start),
+        Some(Err(e)) => {
+            warn!("{}", e);
+            Err(AppError::BadRequest)
+        }
+    }
+}
+
+/// Get a language query parameter, like `audio` (the preferred audio language) or `subtitles`.
+/// Without the parameter, `default` is used. A value of `default` means the station's default.
+fn language_parameter(
+    query: &HashMap<String, String>,
+    name: &str,
+    default: Option<&str>,
+) -> Result<Option<String>, AppError> {
+    match query.get(name).map(|a| a.as_str()).or(default) {
+        None | Some("default") => Ok(None),
+        Some(language)
+            if language
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-') =>
+        {
+            Ok(Some(language.to_owned()))
+        }
+        Some(language) => {
+            warn!("Invalid {} language: {}", name, language);
+            Err(AppError::BadRequest)
+        }
+    }
+}
+
+/// URL of the media playlist of the audio rendition of station `id` in `language`. The playlist is
+/// served by our own HLS passthrough, so ffmpeg doesn't have to deal with expiring URLs. Returns
+/// `None` if there's no separate rendition in that language, or if ffmpeg isn't configured.
+async fn alternate_audio_url<T: StationProvider>(
+    req: &HttpRequest,
+    data: &AppState<T>,
+    id: &str,
+    profile: Option<&str>,
+    policy: &VariantPolicy,
+    language: &str,
+) -> Option<String> {
+    let variants = data.service.station_variants(id).await.ok()?;
+    let rendition =
+        match select_variant(&variants, policy).and_then(|v| audio_rendition(v, language)) {
+            Some(r) if r.url.is_some() => r,
+            Some(_) => {
+                debug!(
+                    "The {} audio of station {} is part of the stream",
+                    language, id
+                );
+                return None;
+            }
+            None => {
+                info!(
+                    "Station {} doesn't offer {} audio, using the default audio",
+                    id, language
+                );
+                return None;
+            }
+        };
+    if data.config.ffmpeg.is_none() {
+        warn!(
+            "Alternate audio requires ffmpeg, using the default audio for station {}",
+            id
+        );
+        return None;
+    }
+    info!("Using {} audio for station {}", rendition.name, id);
+
+    // ffmpeg runs on this machine, so talk to the address we're bound to
+    let address = req.app_config().local_addr();
+    let address = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), address.port())
+        }
+        _ => address,
+    };
+    let mut params = vec![("audio", language)];
+    if let Some(profile) = profile {
+        params.push(("profile", profile));
+    }
+    let url = format!("http://{}/watch/{}/index.m3u8", address, id);
+    Url::parse_with_params(&url, &params)
+        .ok()
+        .map(|u| u.to_string())
+}
+
+/// Get the `transcode` query parameter and the `TranscodeProfile` that goes with it. Transcoding
+/// is only done when `ffmpeg` is configured, otherwise the parameter is ignored, like it is for
+/// `transcode=none`.
+fn transcode_profile(
+    query: &HashMap<String, String>,
+    config: &Config,
+) -> Result<Option<(String, TranscodeProfile)>, AppError> {
+    let name = match query.get("transcode") {
+        Some(name) if name != "none" => name,
+        _ => return Ok(None),
+    };
+    if config.ffmpeg.is_none() {
+        debug!("Transcoding is disabled, ignoring transcode={}", name);
+        return Ok(None);
+    }
+    match config.transcode_profile(name) {
+        Some(profile) => Ok(Some((name.to_owned(), profile))),
+        None => {
+            warn!("Unknown transcode profile: {}", name);
+            Err(AppError::BadRequest)
+        }
+    }
+}
+
 async fn watch_m3u<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
     let id = req.match_info().get("id").unwrap();
-    let service = &req.app_data::<web::Data<AppState<T>>>().unwrap().service;
-    match service.station_stream_uri(id).await {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let query = match query_parameters(&req) {
+        Ok(q) => q,
+        Err(e) => return e.error_response(),
+    };
+    let (_, policy) = match variant_profile(&query, &data.config) {
+        Ok(p) => p,
+        Err(e) => return e.error_response(),
+    };
+
+    // The client streams directly from locast, so we can't tell when it's done and can't hold
+    // on to a tuner. We can still refuse to hand out a stream when all tuners are busy though.
+    if let Err(e) = data.tuners.available(id, &data.service).await {
+        return e.error_response();
+    }
+
+    match data.service.station_stream_uri(id, &policy).await {
         Ok(url_mutex) => {
             let url = url_mutex.lock().await;
 
@@ -407,204 +786,381 @@ async fn watch_m3u<T: 'static + StationProvider>(req: HttpRequest) -> impl Respo
     }
 }
 
-async fn watch<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
+async fn watch<T: 'static + StationProvider + Sync + Send + Clone>(
+    req: HttpRequest,
+) -> impl Responder {
     let id = req.match_info().get("id").unwrap();
-    let service = &req.app_data::<web::Data<AppState<T>>>().unwrap().service;
-    match service.station_stream_uri(id).await {
-        Ok(url_mutex) => {
-            let url = url_mutex.lock().await;
-            let stream = get_stream::<T>(&*url, req);
+    serve_station::<T>(&req, id).await
+}
 
-            HttpResponse::Ok()
-                .content_type("video/mpeg; codecs='avc1.4D401E'")
-                .streaming(Box::pin(stream))
+/// HDHomeRun style stream URL (`/auto/v{GuideNumber}`). The guide number is looked up in the
+/// lineup, so remapped channel numbers work as well.
+async fn watch_auto<T: 'static + StationProvider + Sync + Send + Clone>(
+    req: HttpRequest,
+) -> impl Responder {
+    let guide_number = req.match_info().get("guide_number").unwrap();
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let id = data
+        .service
+        .stations()
+        .await
+        .lock()
+        .await
+        .iter()
+        .filter(|s| s.active)
+        .find(|s| s.channel_remapped.as_deref().or(s.channel.as_deref()) == Some(guide_number))
+        .map(|s| s.id.to_string());
+
+    match id {
+        Some(id) => serve_station::<T>(&req, &id).await,
+        None => {
+            warn!("No station found for guide number {}", guide_number);
+            AppError::NotFound.error_response()
         }
-        Err(e) => e.error_response(),
     }
 }
 
-struct StreamState {
-    segments: VecDeque<Segment>,
-    url: String,
-    stream_id: String,
-    start_time: DateTime<Utc>,
-    seconds_served: f32,
-    req: HttpRequest,
-    count_down: f32,
-}
+/// Stream station `id` to the client as MPEG-TS
+async fn serve_station<T: 'static + StationProvider + Sync + Send + Clone>(
+    req: &HttpRequest,
+    id: &str,
+) -> HttpResponse {
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let query = match query_parameters(req) {
+        Ok(q) => q,
+        Err(e) => return e.error_response(),
+    };
+    let (profile, policy) = match variant_profile(&query, &data.config) {
+        Ok(p) => p,
+        Err(e) => return e.error_response(),
+    };
 
-static COUNT_DOWN: f32 = 9900.0; // 2:45h
-fn get_stream<T: 'static + StationProvider>(
-    url: &str,
-    req: HttpRequest,
-) -> impl Stream<Item = Result<bytes::Bytes, Error>> {
-    // Build helper struct
-    let state = StreamState {
-        segments: VecDeque::new(),
-        url: url.to_owned(),
-        stream_id: Uuid::new_v4().to_string()[0..7].to_string(),
-        start_time: Utc::now(),
-        seconds_served: 0.0,
-        count_down: COUNT_DOWN,
-        req,
-    };
-
-    stream::unfold(state, |mut state| async move {
-        // Refresh initial URL if we've been streaming for `COUNTDOWN seconds`
-        if state.count_down < 0.0 {
-            debug!("Stream {} -  URL expired: {}", state.stream_id, state.url);
-
-            // Get the service and stream id from the state
-            let id = state.req.match_info().get("id").unwrap();
-            let service = &state
-                .req
-                .app_data::<web::Data<AppState<T>>>()
-                .unwrap()
-                .service;
-
-            // Grab a new URL for this stream. If this fails, we end the stream.
-            match service.station_stream_uri(id).await {
-                Ok(url_mutex) => {
-                    let url = url_mutex.lock().await;
-                    debug!("Stream {} - New URL: {}", state.stream_id, &*url);
-                    state.url = (&*url).to_owned();
-                    state.count_down = COUNT_DOWN;
-                }
-                Err(_) => return None,
-            }
-        }
+    let offset = match timeshift_offset(&query) {
+        Ok(o) => o,
+        Err(e) => return e.error_response(),
+    };
+    let start = match stream_start(&query, &data.config) {
+        Ok(s) => s,
+        Err(e) => return e.error_response(),
+    };
+    let audio = match language_parameter(&query, "audio", data.config.audio_language.as_deref()) {
+        Ok(a) => a,
+        Err(e) => return e.error_response(),
+    };
+    let transcode_profile = match transcode_profile(&query, &data.config) {
+        Ok(p) => p,
+        Err(e) => return e.error_response(),
+    };
+    let duration = match stream_duration(&query) {
+        Ok(d) => d,
+        Err(e) => return e.error_response(),
+    };
 
-        // Try fetching the latest m3u playlist. Intermittent errors may happen here,
-        // and when they do, we just skip updating until the next iteration.
-        match crate::utils::get(&state.url, None, 5).await {
-            Ok(r) => Some(r.text().await.unwrap()),
-            Err(e) => {
-                warn!("Unable to get m3u data, skipping a fetch.. {}", e);
-                None
-            }
-        }
-        .and_then(|m3u_data| {
-            // Decode the m3u into a MediaPlaylist.
-            match hls_m3u8::MediaPlaylist::from_str(m3u_data.as_str()) {
-                Ok(p) => Some(p),
-                Err(e) => {
-                    warn!("Unable to decode media playlist, skipping a fetch.. {}", e);
-                    None
-                }
-            }
-        })
-        .map(|media_playlist| {
-            // Loop through each segment and add it to the segments vector
-            // if it's not already in there.
-            for media_segment in media_playlist.segments {
-                let (_i, ms) = media_segment;
-                let absolute_uri = Url::parse(&state.url)
-                    .unwrap()
-                    .join(ms.uri())
-                    .unwrap()
-                    .to_string();
-
-                let s = Segment {
-                    url: absolute_uri,
-                    played: false,
-                    duration: ms.duration.duration(),
-                };
-
-                if !state.segments.contains(&s) {
-                    info!("Stream {} - added segment {:?}", state.stream_id, &s.url);
-                    state.segments.push_back(s);
-                }
-            }
-            Some(())
-        });
-
-        // In order to not grow the state, we drain 10 segments if we have more than
-        // 30. I guess we should check for unplayed segments, but
-        // if we have 30 segments with the first 10 unplayed, we're probably
-        // screwed anyway.
-        if state.segments.len() >= 30 {
-            info!("Stream {} - draining 10 segments", state.stream_id);
-            state.segments.drain(0..10);
+    let tuner = match data.tuners.allocate(id, &data.service).await {
+        Ok(t) => t,
+        Err(e) => return e.error_response(),
+    };
+
+    // Alternate audio is muxed in by ffmpeg
+    let audio_url = match &audio {
+        Some(language) => {
+            alternate_audio_url(req, data, id, profile.as_deref(), &policy, language).await
         }
+        None => None,
+    };
 
-        // Find first unplayed segment and if there are no unplayed segments, we bail
-        let first = match state.segments.iter_mut().find(|s| !s.played) {
-            Some(s) => s,
-            None => {
-                warn!("No first segment found. Stopping stream..");
-                return None;
-            }
-        };
+    let subscription = match offset {
+        // Play from the time-shift buffer
+        Some(offset) => data
+            .timeshift
+            .watch(id, offset)
+            .map(|(stream, status)| (stream.boxed_local(), status)),
+        // Stations that are already being watched share a single upstream session
+        None => data
+            .hubs
+            .subscribe(id, &data.service, profile.as_deref(), policy, start)
+            .await
+            .map(|(stream, status)| (stream.boxed_local(), status)),
+    };
 
-        // Figure out how long we'll wait with serving the next segment. We do this
-        // because otherwise, we constantly loop through our segment list and
-        // make unnecessary calls to locast. This happens because serving a segment
-        // instantly returns, rather than waiting for the client to play
-        // the segment.
-        let runtime = Utc::now() - state.start_time;
+    match subscription {
+        Ok((stream, status)) => {
+            // End the stream after `duration` seconds of video
+            let stream = match duration {
+                Some(duration) => limit_duration(stream, status.clone(), duration).boxed_local(),
+                None => stream,
+            };
 
-        // We want to be 50% of a segment behind. This is a nice trade-off
-        // between having enough time to fetch the next segment and calling
-        // locast too often.
-        let target_diff = 0.5 * first.duration.as_secs_f32();
+            // Transcode the stream or swap its audio if the client asked for it
+            let transcoding = transcode_profile.is_some() || audio_url.is_some();
+            let stream = if transcoding {
+                let ffmpeg = data.config.ffmpeg.as_ref().unwrap();
+                let profile = transcode_profile.as_ref().map(|(n, p)| (n.as_str(), p));
+                match transcode(stream, ffmpeg, profile, audio_url.as_deref()) {
+                    Ok(s) => s.boxed_local(),
+                    Err(e) => return e.error_response(),
+                }
+            } else {
+                stream
+            };
 
-        let wait = if state.seconds_served > 0.0 {
-            state.seconds_served - target_diff - (runtime.num_milliseconds() as f32 / 1000.0)
-        } else {
-            0.0
-        };
+            let (call_sign, media_info) = data
+                .service
+                .stations()
+                .await
+                .lock()
+                .await
+                .iter()
+                .find(|s| s.id.to_string() == id)
+                .map(|s| (s.callSign.to_owned(), s.media_info.to_owned()))
+                .unwrap_or_default();
 
-        info!(
-            "Serving {} ({} s) in {}s",
-            &first.url,
-            first.duration.as_secs_f32(),
-            wait
-        );
+            // The codecs are known once the station has been tuned before. ffmpeg may change them.
+            let content_type = media_info
+                .filter(|_| !transcoding)
+                .and_then(|m| m.content_type())
+                .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_owned());
+            let client_address = req
+                .connection_info()
+                .realip_remote_addr()
+                .unwrap_or("unknown")
+                .to_owned();
+            let user_agent = req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|u| u.to_str().ok())
+                .unwrap_or("unknown");
+            let stream =
+                data.sessions
+                    .track(stream, id, &call_sign, &client_address, user_agent, status);
 
-        if wait > 0.0 {
-            tokio::time::sleep(tokio::time::Duration::from_secs_f32(wait)).await;
+            HttpResponse::Ok()
+                .content_type(content_type)
+                .streaming(Box::pin(tuner.hold(stream).map(Ok::<_, Error>)))
         }
+        Err(e) => e.error_response(),
+    }
+}
 
-        // Download the actual chunk from locast
-        let chunk = match crate::utils::get(&first.url, None, 10).await {
-            Err(e) => {
-                warn!("No bytes fetched.. Stopping stream.. {}", e);
-                return None;
-            }
-            Ok(r) => match r.bytes().await {
-                Ok(b) => b.to_vec(),
-                Err(e) => {
-                    warn!("No bytes fetched.. Stopping stream.. {}", e);
-                    return None;
-                }
-            },
-        };
+/// All streams that are currently being served to clients
+async fn streams<T: 'static + StationProvider>(data: web::Data<AppState<T>>) -> impl Responder {
+    HttpResponse::Ok().json(data.sessions.list())
+}
 
-        // Mark the segment as played
-        first.played = true;
-        info!(
-            "Stream {} - playing: segment {:?}",
-            state.stream_id, first.url
-        );
+/// Terminate a stream that is being served to a client
+async fn kill_stream<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
+    let id = req.match_info().get("id").unwrap();
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    match data.sessions.kill(id) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => e.error_response(),
+    }
+}
 
-        state.seconds_served += first.duration.as_secs_f32();
-        state.count_down -= first.duration.as_secs_f32();
-        Some((Ok(actix_web::web::Bytes::from(chunk)), state))
-    })
+/// HLS media playlist for a station. Segment URIs point back at locast2tuner, so clients that
+/// play HLS natively never talk to locast directly.
+async fn watch_hls<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
+    let id = req.match_info().get("id").unwrap();
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let host = req.connection_info().host().to_string();
+    let base_url = format!("http://{}/watch/{}", host, id);
+    let query = match query_parameters(&req) {
+        Ok(q) => q,
+        Err(e) => return e.error_response(),
+    };
+    let (profile, policy) = match variant_profile(&query, &data.config) {
+        Ok(p) => p,
+        Err(e) => return e.error_response(),
+    };
+    let audio = match language_parameter(&query, "audio", None) {
+        Ok(a) => a,
+        Err(e) => return e.error_response(),
+    };
+    let subtitles = match language_parameter(&query, "subtitles", None) {
+        Ok(s) => s,
+        Err(e) => return e.error_response(),
+    };
+    let track = match (&audio, &subtitles) {
+        (_, Some(language)) => Track::Subtitles(language),
+        (Some(language), None) => Track::Audio(language),
+        (None, None) => Track::Main,
+    };
+
+    match data
+        .hls
+        .playlist(
+            id,
+            &data.service,
+            profile.as_deref(),
+            &policy,
+            track,
+            &base_url,
+        )
+        .await
+    {
+        Ok(playlist) => HttpResponse::Ok()
+            .content_type("application/vnd.apple.mpegurl")
+            .body(playlist),
+        Err(e) => e.error_response(),
+    }
 }
 
-async fn lineup_post(_req: HttpRequest) -> impl Responder {
-    HttpResponse::NoContent()
+/// HLS master playlist for a station, which adds the station's subtitles to the media playlist
+/// that `watch_hls` serves
+async fn watch_hls_master<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
+    let id = req.match_info().get("id").unwrap();
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let host = req.connection_info().host().to_string();
+    let query = match query_parameters(&req) {
+        Ok(q) => q,
+        Err(e) => return e.error_response(),
+    };
+    let (profile, policy) = match variant_profile(&query, &data.config) {
+        Ok(p) => p,
+        Err(e) => return e.error_response(),
+    };
+
+    let mut playlist_url = match Url::parse(&format!("http://{}/watch/{}/index.m3u8", host, id)) {
+        Ok(u) => u,
+        Err(_) => return AppError::BadRequest.error_response(),
+    };
+    if let Some(profile) = &profile {
+        playlist_url
+            .query_pairs_mut()
+            .append_pair("profile", profile);
+    }
+
+    match master_playlist(id, &data.service, &policy, &playlist_url).await {
+        Ok(playlist) => HttpResponse::Ok()
+            .content_type("application/vnd.apple.mpegurl")
+            .body(playlist),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Live subtitles of a station as a single WebVTT document, for clients that can't decode the
+/// captions in the video stream
+async fn watch_subtitles<T: 'static + StationProvider + Clone>(req: HttpRequest) -> impl Responder {
+    let id = req.match_info().get("id").unwrap();
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let query = match query_parameters(&req) {
+        Ok(q) => q,
+        Err(e) => return e.error_response(),
+    };
+    let (_, policy) = match variant_profile(&query, &data.config) {
+        Ok(p) => p,
+        Err(e) => return e.error_response(),
+    };
+    let language = match language_parameter(&query, "language", None) {
+        Ok(l) => l,
+        Err(e) => return e.error_response(),
+    };
+
+    match subtitles(data.service.clone(), id, policy, language.as_deref()).await {
+        Ok(stream) => HttpResponse::Ok()
+            .content_type("text/vtt; charset=utf-8")
+            .streaming(Box::pin(stream.map(Ok::<_, Error>))),
+        Err(e) => e.error_response(),
+    }
 }
 
-#[derive(Debug)]
-struct Segment {
-    url: String,
-    played: bool,
-    duration: std::time::Duration,
+async fn watch_hls_segment<T: 'static + StationProvider>(req: HttpRequest) -> impl Responder {
+    let id = req.match_info().get("id").unwrap();
+    let segment = req.match_info().get("segment").unwrap();
+    let data = &req.app_data::<web::Data<AppState<T>>>().unwrap();
+
+    let content_type = if req.path().ends_with(".vtt") {
+        "text/vtt"
+    } else if req.path().ends_with(".key") {
+        "application/octet-stream"
+    } else {
+        "video/mp2t"
+    };
+
+    match data.hls.segment(id, segment).await {
+        Ok(stream) => HttpResponse::Ok()
+            .content_type(content_type)
+            .streaming(Box::pin(stream.map(Ok::<_, Error>))),
+        Err(e) => e.error_response(),
+    }
 }
-impl PartialEq for Segment {
-    fn eq(&self, other: &Self) -> bool {
-        self.url == other.url
+
+/// Start (`scan=start`) or abort (`scan=abort`) a channel scan. A scan rebuilds the station list,
+/// so `lineup.json` is up to date with locast afterwards. Progress is reported through
+/// `lineup_status.json`. Favorites are toggled with `favorite`. Other posts are ignored.
+async fn lineup_post<T: 'static + StationProvider + Send + Sync + Clone>(
+    req: HttpRequest,
+) -> HttpResponse {
+    let data = req.app_data::<web::Data<AppState<T>>>().unwrap();
+    let query = match query_parameters(&req) {
+        Ok(q) => q,
+        Err(e) => return e.error_response(),
+    };
+
+    if let Some(favorite) = query.get("favorite") {
+        return match set_favorite(data, favorite).await {
+            Ok(_) => HttpResponse::NoContent().finish(),
+            Err(e) => e.error_response(),
+        };
+    }
+
+    let mut station_scan = data.station_scan.lock().await;
+    let scanning = matches!(station_scan.as_ref(), Some(s) if !s.progress.finished());
+    match query.get("scan").map(|s| s.as_str()) {
+        Some("start") if !scanning => {
+            info!("Scanning channels for {}..", data.service.geo().name);
+            let progress = Arc::new(ScanProgress::new(data.service.services().len()));
+            let service = data.service.clone();
+            let task = {
+                let progress = progress.clone();
+                tokio::spawn(async move {
+                    service.scan(progress.clone()).await;
+                    progress.finish();
+                    info!("Channel scan done, found {} stations", progress.found());
+                })
+            };
+            *station_scan = Some(Scan { progress, task });
+        }
+        Some("start") => {}
+        Some("abort") => {
+            if let Some(scan) = station_scan.as_ref().filter(|_| scanning) {
+                info!("Aborting channel scan for {}", data.service.geo().name);
+                scan.task.abort();
+                scan.progress.finish();
+            }
+        }
+        // HDHomeRun clients also post without parameters, which doesn't do anything
+        _ => {}
     }
+    HttpResponse::NoContent().finish()
+}
+
+/// Add (`+<guide number>`) or remove (`-<guide number>`) a favorite station. Note that an unescaped
+/// `+` in a query string turns into a space.
+async fn set_favorite<T: StationProvider>(
+    data: &AppState<T>,
+    favorite: &str,
+) -> Result<(), AppError> {
+    let (add, guide_number) = match favorite.chars().next() {
+        Some('+') | Some(' ') => (true, &favorite[1..]),
+        Some('-') => (false, &favorite[1..]),
+        _ => return Err(AppError::BadRequest),
+    };
+    let stations = data.service.stations().await;
+    let stations = stations.lock().await;
+    let station = stations
+        .iter()
+        .find(|s| {
+            s.channel_remapped.as_deref().or(s.channel.as_deref()) == Some(guide_number.trim())
+        })
+        .ok_or(AppError::NotFound)?;
+    info!(
+        "{} {} ({}) {} favorites",
+        if add { "Adding" } else { "Removing" },
+        guide_number.trim(),
+        station.name,
+        if add { "to" } else { "from" }
+    );
+    data.favorites.set(&station.id.to_string(), add);
+    Ok(())
 }
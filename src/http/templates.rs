@@ -65,6 +65,8 @@ pub fn epg_xml(stations: &[Station]) -> String {
         }
         for station in (stations){
             let timezone = station.timezone.as_ref().unwrap().parse::<Tz>().unwrap();
+            let video = station.media_info.as_ref().and_then(|m| m.video.as_ref());
+            let audio = station.media_info.as_ref().and_then(|m| m.audio.as_ref());
             for program in (&station.listings) {
                 <programme start={format_time(program.startTime)}  stop={format_time(program.startTime + program.duration * 1000)} channel={format!("channel.{}",station.id)}>
                     <title lang="en">{encode_minimal(&program.title)}</title>
@@ -115,7 +117,13 @@ pub fn epg_xml(stations: &[Station]) -> String {
                     }
 
                     <episode-num system="dd_progid">{program.programId}</episode-num>
-                    if let Some(video_properties) = (&program.videoProperties){
+                    if let Some(video) = (video) {
+                        <video>
+                            <present>{"yes"}</present>
+                            <aspect>{video.aspect_ratio()}</aspect>
+                            <quality>{video.quality()}</quality>
+                        </video>
+                    } else if let Some(video_properties) = (&program.videoProperties){
                         <video>
                             <present>{"yes"}</present>
                             <aspect>{aspect_ratio(video_properties)}</aspect>
@@ -125,7 +133,7 @@ pub fn epg_xml(stations: &[Station]) -> String {
 
                     <audio>
                     <present>{"yes"}</present>
-                    <stereo>{"stereo"}</stereo>
+                    <stereo>{audio.map(|a| a.stereo()).unwrap_or("stereo")}</stereo>
                     </audio>
 
                     if (program.isNew.is_some() && *program.isNew.as_ref().unwrap()){
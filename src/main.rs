@@ -3,11 +3,13 @@
 extern crate log;
 mod config;
 mod credentials;
+mod discovery;
 mod errors;
 mod fcc_facilities;
 mod http;
 mod logging;
 mod service;
+mod streaming;
 mod utils;
 use itertools::Itertools;
 use rand::seq::SliceRandom;
@@ -66,6 +68,16 @@ async fn main() -> Result<(), SimpleError> {
         running_in_docker
     );
 
+    if conf.reset_device_ids {
+        match discovery::reset(&conf.cache_directory) {
+            Ok(_) => info!(
+                "Device IDs have been reset. Tuners will be seen as new devices on the next start"
+            ),
+            Err(e) => error!("Unable to reset device IDs: {}", e),
+        }
+        return Ok(());
+    }
+
     debug!("Main UUID: {}", conf.clone().uuid);
 
     info!("Consider sponsoring this project at https://github.com/sponsors/wouterdebie!");
@@ -0,0 +1,47 @@
+use crate::utils::{load_json, save_json};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// File in the cache directory that holds the favorite stations
+static FAVORITES_FILE: &str = "favorites";
+
+/// Favorite stations by locast station id. Favorites are toggled through `lineup.post`, like on a
+/// real HDHomeRun, and are stored in the cache directory, so they survive restarts and channel
+/// number changes.
+pub struct Favorites {
+    path: PathBuf,
+    ids: Mutex<BTreeSet<String>>,
+}
+
+impl Favorites {
+    /// Load the favorites from the cache directory
+    pub fn load(cache_directory: &Path) -> Favorites {
+        let path = cache_directory.join(FAVORITES_FILE);
+        let ids = load_json(&path, "favorites");
+        Favorites {
+            path,
+            ids: Mutex::new(ids),
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.lock().unwrap().contains(id)
+    }
+
+    /// Add (`favorite` is true) or remove station `id` from the favorites
+    pub fn set(&self, id: &str, favorite: bool) {
+        let mut ids = self.ids.lock().unwrap();
+        let changed = if favorite {
+            ids.insert(id.to_owned())
+        } else {
+            ids.remove(id)
+        };
+        if !changed {
+            return;
+        }
+        save_json(&self.path, &*ids, "favorites");
+    }
+}
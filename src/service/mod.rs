@@ -1,13 +1,22 @@
+pub mod favorites;
 pub mod multiplexer;
+pub mod scan;
 pub mod station;
 pub mod station_provider;
+pub mod variant;
 use self::{
+    scan::ScanProgress,
     station::{Station, Stations},
     station_provider::StationProvider,
+    variant::{audio_languages, select_variant, variants, Variant},
 };
 use crate::{
-    config::Config, credentials::LocastCredentials, errors::AppError,
-    fcc_facilities::FCCFacilities, utils::get,
+    config::{Config, VariantPolicy},
+    credentials::LocastCredentials,
+    errors::AppError,
+    fcc_facilities::FCCFacilities,
+    streaming::probe::MediaInfo,
+    utils::get,
 };
 use async_trait::async_trait;
 use chrono::Utc;
@@ -15,16 +24,16 @@ use futures::lock::Mutex;
 use lazy_static::lazy_static;
 use log::info;
 use regex::Regex;
-use reqwest::{Error, Url};
+use reqwest::Error;
 use serde::Deserialize;
 use serde_json::Value;
 use std::{
-    borrow::Cow,
     collections::HashMap,
     convert::{From, TryFrom},
     fmt,
     str::FromStr,
     sync::Arc,
+    time::Instant,
 };
 use tokio::task;
 use tokio::time::{sleep, Duration};
@@ -34,6 +43,10 @@ static IP_URL: &str = "https://api.locastnet.org/api/watch/dma/ip";
 static STATIONS_URL: &str = "https://api.locastnet.org/api/watch/epg";
 static WATCH_URL: &str = "https://api.locastnet.org/api/watch/station";
 
+// How long resolved stream URLs are reused. This makes switching back and forth between stations
+// a lot faster, while the URLs are still fresh enough to be used.
+static STREAM_URI_CACHE_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Struct that interacts with locast. Note that valid credentials are required
 #[derive(Debug)]
 pub struct LocastService {
@@ -44,6 +57,9 @@ pub struct LocastService {
     pub geo: Arc<Geo>,
     pub uuid: String,
     stations: Stations,
+    variants_cache: Mutex<HashMap<String, (Instant, Vec<Variant>)>>,
+    audio_languages: Mutex<HashMap<String, Vec<String>>>,
+    media_info: Mutex<HashMap<String, MediaInfo>>,
 }
 
 impl LocastService {
@@ -71,6 +87,7 @@ impl LocastService {
                 &geo,
                 &config,
                 &fcc_facilities,
+                None,
             )
             .await,
         ));
@@ -83,6 +100,9 @@ impl LocastService {
             geo,
             uuid,
             stations,
+            variants_cache: Mutex::new(HashMap::new()),
+            audio_languages: Mutex::new(HashMap::new()),
+            media_info: Mutex::new(HashMap::new()),
         });
 
         // Start an updater thread that will periodically update all station information
@@ -91,37 +111,8 @@ impl LocastService {
         service
     }
 
-    /// Convenience method for building stations based on &self
-    async fn build_stations(&self) -> Vec<Station> {
-        let locast_stations = locast_stations(
-            &self.geo.DMA,
-            self.config.days,
-            &self.credentials.token().await,
-        )
-        .await;
-        build_stations(
-            locast_stations,
-            &self.geo,
-            &self.config,
-            &self.fcc_facilities,
-        )
-        .await
-    }
-}
-
-#[async_trait]
-impl StationProvider for Arc<LocastService> {
-    /// Get stations
-    async fn stations(&self) -> Stations {
-        if self.config.disable_station_cache {
-            Arc::new(Mutex::new(self.build_stations().await))
-        } else {
-            self.stations.clone()
-        }
-    }
-
-    /// Get the stream URI for a specified station id
-    async fn station_stream_uri(&self, id: &str) -> Result<Mutex<String>, AppError> {
+    /// Resolve the variant streams of a station through locast
+    async fn fetch_variants(&self, id: &str) -> Result<Vec<Variant>, AppError> {
         // Construct the URL for the station
         let url = format!(
             "{}/{}/{}/{}",
@@ -154,16 +145,159 @@ impl StationProvider for Arc<LocastService> {
             .unwrap();
         let master_playlist = hls_m3u8::MasterPlaylist::try_from(m3u_data.as_str());
 
-        // If there's a master playlist, parse it and get the highest quality stream, else we already have the
-        // correct URL.
-        match master_playlist {
-            Ok(mp) => Ok(Mutex::new(highest_quality_url(
-                mp.variant_streams,
-                &stream_url,
-            ))),
-            Err(_) => Ok(Mutex::new(stream_url.to_owned())),
+        // If there's a master playlist, parse it and return its variant streams, else we already
+        // have the correct URL.
+        let variants = match master_playlist {
+            Ok(mp) => variants(&mp.variant_streams, &mp.media, &stream_url),
+            Err(_) => vec![],
+        };
+        if variants.is_empty() {
+            Ok(vec![Variant {
+                url: stream_url,
+                bandwidth: 0,
+                resolution: None,
+                codecs: None,
+                frame_rate: None,
+                audio: vec![],
+                subtitles: vec![],
+            }])
+        } else {
+            Ok(variants)
+        }
+    }
+
+    /// Convenience method for building stations based on &self
+    async fn build_stations(&self, progress: Option<&ScanProgress>) -> Vec<Station> {
+        let locast_stations = locast_stations(
+            &self.geo.DMA,
+            self.config.days,
+            &self.credentials.token().await,
+        )
+        .await;
+        let mut stations = build_stations(
+            locast_stations,
+            &self.geo,
+            &self.config,
+            &self.fcc_facilities,
+            progress,
+        )
+        .await;
+        self.apply_station_details(&mut stations).await;
+        stations
+    }
+
+    /// Remember the alternate audio languages of station `id`. Languages are only known once a
+    /// station has been tuned, since that's when its master playlist is fetched.
+    async fn record_audio_languages(&self, id: &str, variants: &[Variant]) {
+        let languages = audio_languages(variants);
+        self.audio_languages
+            .lock()
+            .await
+            .insert(id.to_owned(), languages.clone());
+        if let Some(station) = self
+            .stations
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.id.to_string() == id)
+        {
+            station.audio_languages = Some(languages).filter(|l| !l.is_empty());
+        }
+    }
+
+    /// Set the alternate audio languages and codecs we know of on freshly built `stations`
+    async fn apply_station_details(&self, stations: &mut [Station]) {
+        let languages = self.audio_languages.lock().await;
+        let media_info = self.media_info.lock().await;
+        for station in stations.iter_mut() {
+            let id = station.id.to_string();
+            station.audio_languages = languages.get(&id).filter(|l| !l.is_empty()).cloned();
+            station.media_info = media_info.get(&id).cloned();
         }
     }
+}
+
+#[async_trait]
+impl StationProvider for Arc<LocastService> {
+    /// Get stations
+    async fn stations(&self) -> Stations {
+        if self.config.disable_station_cache {
+            Arc::new(Mutex::new(self.build_stations(None).await))
+        } else {
+            self.stations.clone()
+        }
+    }
+
+    /// Get the stream URI for a specified station id. The variant stream is picked based on `policy`.
+    async fn station_stream_uri(
+        &self,
+        id: &str,
+        policy: &VariantPolicy,
+    ) -> Result<Mutex<String>, AppError> {
+        let variants = self.station_variants(id).await?;
+        let variant = select_variant(&variants, policy).unwrap();
+        debug!(
+            "Selected variant for {}: {} bit/s, {:?}, {:?} ({:?})",
+            id, variant.bandwidth, variant.resolution, variant.codecs, policy
+        );
+        Ok(Mutex::new(variant.url.to_owned()))
+    }
+
+    /// Get all variant streams for a specified station id, sorted by bandwidth (asc). If the
+    /// stream doesn't have a master playlist, a single variant is returned. Results are cached for
+    /// `STREAM_URI_CACHE_TIMEOUT`, so quickly tuning back and forth doesn't hit locast every time.
+    async fn station_variants(&self, id: &str) -> Result<Vec<Variant>, AppError> {
+        if let Some((resolved, variants)) = self.variants_cache.lock().await.get(id) {
+            if resolved.elapsed() < STREAM_URI_CACHE_TIMEOUT {
+                debug!("Using cached stream URL for {}", id);
+                return Ok(variants.clone());
+            }
+        }
+
+        self.refresh_station_variants(id).await
+    }
+
+    /// Resolve fresh variant streams for a station id, bypassing the cache. This is used when a
+    /// stream URL expired or was rejected, in which case the cached URL is no good either.
+    async fn refresh_station_variants(&self, id: &str) -> Result<Vec<Variant>, AppError> {
+        let variants = self.fetch_variants(id).await?;
+        self.record_audio_languages(id, &variants).await;
+        let mut cache = self.variants_cache.lock().await;
+        cache.retain(|_, (resolved, _)| resolved.elapsed() < STREAM_URI_CACHE_TIMEOUT);
+        cache.insert(id.to_owned(), (Instant::now(), variants.clone()));
+        Ok(variants)
+    }
+
+    /// Remember the codecs of a station. Codecs are detected when a station is tuned, so they are
+    /// kept across station updates.
+    async fn record_media_info(&self, id: &str, info: MediaInfo) {
+        self.media_info
+            .lock()
+            .await
+            .insert(id.to_owned(), info.clone());
+        if let Some(station) = self
+            .stations
+            .lock()
+            .await
+            .iter_mut()
+            .find(|s| s.id.to_string() == id)
+        {
+            station.media_info = Some(info);
+        }
+    }
+
+    /// Rebuild the station list, like a tuner does when it scans for channels. The current
+    /// stations are kept until the new ones have been built.
+    async fn scan(&self, progress: Arc<ScanProgress>) {
+        let stations = self.build_stations(Some(&progress)).await;
+        *self.stations.lock().await = stations;
+        progress.service_done();
+    }
+
+    /// Returns the UUID of the service that serves a station. This is always this service.
+    async fn station_service_uuid(&self, _id: &str) -> Result<String, AppError> {
+        Ok(self.uuid())
+    }
 
     /// Returns the `Geo` that is associated with this service
     fn geo(&self) -> Arc<Geo> {
@@ -191,38 +325,6 @@ impl StationProvider for Arc<LocastService> {
     }
 }
 
-/// Sort the variant streams by bandwidth (desc), pick the top one and return the full URL
-fn highest_quality_url(
-    mut variant_streams: Vec<hls_m3u8::tags::VariantStream>,
-    stream_url: &str,
-) -> String {
-    variant_streams.sort_by(|a, b| {
-        let ea = match a {
-            hls_m3u8::tags::VariantStream::ExtXStreamInf { stream_data, .. } => {
-                stream_data.bandwidth()
-            }
-            _ => 0,
-        };
-        let eb = match b {
-            hls_m3u8::tags::VariantStream::ExtXStreamInf { stream_data, .. } => {
-                stream_data.bandwidth()
-            }
-            _ => 0,
-        };
-        ea.cmp(&eb)
-    });
-    let variant = variant_streams.pop().unwrap();
-    let variant_url = match variant {
-        hls_m3u8::tags::VariantStream::ExtXStreamInf { uri, .. } => uri,
-        _ => Cow::Borrowed(""),
-    };
-    Url::parse(stream_url)
-        .unwrap()
-        .join(&variant_url.to_string())
-        .unwrap()
-        .to_string()
-}
-
 impl fmt::Display for LocastService {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -244,20 +346,29 @@ fn start_updater_thread(service: Arc<LocastService>) {
                 &service.credentials.token().await,
             )
             .await;
-            let new_stations =
-                build_stations(ls, &service.geo, &service.config, &service.fcc_facilities).await;
+            let mut new_stations = build_stations(
+                ls,
+                &service.geo,
+                &service.config,
+                &service.fcc_facilities,
+                None,
+            )
+            .await;
+            service.apply_station_details(&mut new_stations).await;
             let mut stations = service.stations.lock().await;
             *stations = new_stations;
         }
     });
 }
 
-/// Retrieve and enrich station data
+/// Retrieve and enrich station data. When the stations are built for a channel scan, every station
+/// that has been looked up is reported to `progress`.
 async fn build_stations(
     locast_stations: Vec<Station>,
     geo: &Geo,
     config: &Arc<Config>,
     fcc_facilities: &Arc<FCCFacilities>,
+    progress: Option<&ScanProgress>,
 ) -> Vec<Station> {
     info!(
         "Loading stations for {} (cache: {}, cache timeout: {}, days: {})..",
@@ -265,6 +376,9 @@ async fn build_stations(
     );
 
     let mut stations: Vec<Station> = Vec::new();
+    if let Some(p) = progress {
+        p.start_service(locast_stations.len());
+    }
 
     // Iterate over all locast stations for this service
     for mut station in locast_stations.into_iter() {
@@ -297,6 +411,9 @@ async fn build_stations(
         station.channel = c;
         // Rewrite the callsign to remove the channel number
         station.callSign = crate::utils::name_only(&station.callSign).to_owned();
+        if let Some(p) = progress {
+            p.station_done(station.active);
+        }
         stations.push(station);
     }
     stations
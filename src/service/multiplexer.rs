@@ -1,8 +1,9 @@
-use super::station::ChannelRemapEntry;
+use super::{station::ChannelRemapEntry, variant::Variant};
 use crate::{
-    config::Config,
+    config::{Config, VariantPolicy},
     errors::AppError,
-    service::{Geo, LocastService, Station, StationProvider, Stations},
+    service::{scan::ScanProgress, Geo, LocastService, Station, StationProvider, Stations},
+    streaming::probe::MediaInfo,
 };
 use async_trait::async_trait;
 use futures::lock::Mutex;
@@ -36,21 +37,63 @@ impl Multiplexer {
             channel_remap,
         })
     }
+
+    /// Find the `LocastService` that serves a locast station id
+    async fn service_for(self: &Arc<Self>, id: &str) -> Result<Arc<LocastService>, AppError> {
+        // Make sure the station_id_service_map is loaded. Feels wrong to do it like this though.. Needs refactoring.
+        self.stations().await;
+
+        match self.station_id_service_map.lock().await.get(&id.to_owned()) {
+            Some(s) => Ok(s.clone()),
+            None => Err(AppError::NotFound),
+        }
+    }
 }
 
 #[async_trait]
 impl StationProvider for Arc<Multiplexer> {
     /// Get the stream URL for a locast station id.
-    async fn station_stream_uri(&self, id: &str) -> Result<Mutex<String>, AppError> {
-        // Make sure the station_id_service_map is loaded. Feels wrong to do it like this though.. Needs refactoring.
-        self.stations().await;
+    async fn station_stream_uri(
+        &self,
+        id: &str,
+        policy: &VariantPolicy,
+    ) -> Result<Mutex<String>, AppError> {
+        self.service_for(id)
+            .await?
+            .station_stream_uri(id, policy)
+            .await
+    }
 
-        let service = match self.station_id_service_map.lock().await.get(&id.to_owned()) {
-            Some(s) => s.clone(),
-            None => return Err(AppError::NotFound),
-        };
+    /// Get all variant streams for a locast station id.
+    async fn station_variants(&self, id: &str) -> Result<Vec<Variant>, AppError> {
+        self.service_for(id).await?.station_variants(id).await
+    }
+
+    /// Resolve fresh variant streams for a locast station id.
+    async fn refresh_station_variants(&self, id: &str) -> Result<Vec<Variant>, AppError> {
+        self.service_for(id)
+            .await?
+            .refresh_station_variants(id)
+            .await
+    }
+
+    /// Remember the codecs of a locast station id.
+    async fn record_media_info(&self, id: &str, info: MediaInfo) {
+        if let Ok(service) = self.service_for(id).await {
+            service.record_media_info(id, info).await
+        }
+    }
+
+    /// Scan all `LocastService`s, one after another.
+    async fn scan(&self, progress: Arc<ScanProgress>) {
+        for service in &self.services {
+            service.scan(progress.clone()).await;
+        }
+    }
 
-        service.station_stream_uri(id).await
+    /// Get the UUID of the `LocastService` that serves a locast station id.
+    async fn station_service_uuid(&self, id: &str) -> Result<String, AppError> {
+        Ok(self.service_for(id).await?.uuid())
     }
 
     /// Get all stations for all `LocastService`s.
@@ -0,0 +1,84 @@
+use std::sync::Mutex;
+
+/// Progress of a channel scan. A scan refreshes the stations of one or more `LocastService`s, one
+/// after another. Every service gets an equal share of the progress.
+#[derive(Debug)]
+pub struct ScanProgress {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    services: usize,
+    services_done: usize,
+    stations: usize,
+    stations_done: usize,
+    found: usize,
+    finished: bool,
+}
+
+impl ScanProgress {
+    /// Create a `ScanProgress` for a scan of `services` services
+    pub fn new(services: usize) -> ScanProgress {
+        ScanProgress {
+            inner: Mutex::new(Inner {
+                services: services.max(1),
+                services_done: 0,
+                stations: 0,
+                stations_done: 0,
+                found: 0,
+                finished: false,
+            }),
+        }
+    }
+
+    /// A service starts looking up `stations` stations
+    pub fn start_service(&self, stations: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.stations = stations;
+        inner.stations_done = 0;
+    }
+
+    /// A station has been looked up. Only `active` stations count as found.
+    pub fn station_done(&self, active: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.stations_done += 1;
+        if active {
+            inner.found += 1;
+        }
+    }
+
+    /// A service has been scanned
+    pub fn service_done(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.services_done += 1;
+        inner.stations = 0;
+        inner.stations_done = 0;
+    }
+
+    /// The scan has ended, either because all services were scanned or because it was aborted
+    pub fn finish(&self) {
+        self.inner.lock().unwrap().finished = true;
+    }
+
+    pub fn finished(&self) -> bool {
+        self.inner.lock().unwrap().finished
+    }
+
+    /// Progress in percent
+    pub fn progress(&self) -> u8 {
+        let inner = self.inner.lock().unwrap();
+        let current = if inner.stations > 0 {
+            inner.stations_done as f64 / inner.stations as f64
+        } else {
+            0.0
+        };
+        let done = (inner.services_done as f64 + current) / inner.services as f64;
+        (done * 100.0).min(100.0) as u8
+    }
+
+    /// Number of stations found so far
+    pub fn found(&self) -> usize {
+        self.inner.lock().unwrap().found
+    }
+}
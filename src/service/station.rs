@@ -1,5 +1,6 @@
 use std::sync::Arc;
 
+use crate::streaming::probe::MediaInfo;
 use futures::lock::Mutex;
 use serde::{Deserialize, Serialize};
 #[allow(non_snake_case)]
@@ -24,6 +25,10 @@ pub struct Station {
     pub channel_remapped: Option<String>,
     pub callSign_remapped: Option<String>,
     pub remapped: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_languages: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_info: Option<MediaInfo>,
 }
 pub type Stations = Arc<Mutex<Vec<Station>>>;
 
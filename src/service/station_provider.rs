@@ -1,13 +1,22 @@
-use crate::errors::AppError;
+use crate::{config::VariantPolicy, errors::AppError, streaming::probe::MediaInfo};
 
-use super::{station::Stations, Geo, LocastService};
+use super::{scan::ScanProgress, station::Stations, variant::Variant, Geo, LocastService};
 use async_trait::async_trait;
 use futures::lock::Mutex;
 use std::sync::Arc;
 
 #[async_trait]
 pub trait StationProvider {
-    async fn station_stream_uri(&self, id: &str) -> Result<Mutex<String>, AppError>;
+    async fn station_stream_uri(
+        &self,
+        id: &str,
+        policy: &VariantPolicy,
+    ) -> Result<Mutex<String>, AppError>;
+    async fn station_variants(&self, id: &str) -> Result<Vec<Variant>, AppError>;
+    async fn refresh_station_variants(&self, id: &str) -> Result<Vec<Variant>, AppError>;
+    async fn record_media_info(&self, id: &str, info: MediaInfo);
+    async fn scan(&self, progress: Arc<ScanProgress>);
+    async fn station_service_uuid(&self, id: &str) -> Result<String, AppError>;
     async fn stations(&self) -> Stations;
     fn geo(&self) -> Arc<Geo>;
     fn uuid(&self) -> String;
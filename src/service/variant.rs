@@ -0,0 +1,209 @@
+use crate::config::VariantPolicy;
+use hls_m3u8::{
+    tags::{ExtXMedia, VariantStream},
+    types::MediaType,
+};
+use reqwest::Url;
+
+/// A variant stream from a master playlist, with the attributes we use to pick one
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub url: String,
+    pub bandwidth: u64,
+    pub resolution: Option<(usize, usize)>,
+    pub codecs: Option<String>,
+    pub frame_rate: Option<f32>,
+    pub audio: Vec<Rendition>,
+    pub subtitles: Vec<Rendition>,
+}
+
+/// An alternative rendition (`EXT-X-MEDIA`) of a variant stream, e.g. a second audio language
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    pub name: String,
+    pub language: Option<String>,
+    /// URL of the media playlist of the rendition. `None` means the rendition is part of the
+    /// variant stream itself.
+    pub url: Option<String>,
+}
+
+// ISO 639-1 codes and their ISO 639-2 equivalents, so `spa` matches a rendition tagged `es`
+static LANGUAGE_CODES: [(&str, &str); 16] = [
+    ("ar", "ara"),
+    ("de", "deu"),
+    ("de", "ger"),
+    ("en", "eng"),
+    ("es", "spa"),
+    ("fr", "fra"),
+    ("fr", "fre"),
+    ("hi", "hin"),
+    ("it", "ita"),
+    ("ja", "jpn"),
+    ("ko", "kor"),
+    ("pt", "por"),
+    ("ru", "rus"),
+    ("tl", "tgl"),
+    ("vi", "vie"),
+    ("zh", "zho"),
+];
+
+/// Get all `EXT-X-STREAM-INF` variants from a master playlist, sorted by bandwidth (asc), together
+/// with the audio and subtitle renditions in `media` that go with them. URLs are made absolute using
+/// `stream_url`.
+pub fn variants(
+    variant_streams: &[VariantStream],
+    media: &[ExtXMedia],
+    stream_url: &str,
+) -> Vec<Variant> {
+    let base_url = Url::parse(stream_url).unwrap();
+    let mut variants: Vec<Variant> = variant_streams
+        .iter()
+        .filter_map(|v| match v {
+            VariantStream::ExtXStreamInf {
+                uri,
+                frame_rate,
+                stream_data,
+                audio,
+                subtitles,
+                ..
+            } => Some(Variant {
+                url: base_url.join(uri).unwrap().to_string(),
+                bandwidth: stream_data.bandwidth(),
+                resolution: stream_data.resolution().map(|r| r.into()),
+                codecs: stream_data.codecs().map(|c| c.to_string()),
+                frame_rate: frame_rate.map(|f| f.as_f32()),
+                audio: renditions(media, MediaType::Audio, audio.as_deref(), &base_url),
+                subtitles: renditions(media, MediaType::Subtitles, subtitles.as_deref(), &base_url),
+            }),
+            _ => None,
+        })
+        .collect();
+    variants.sort_by_key(|v| v.bandwidth);
+    variants
+}
+
+/// Get the renditions of `media_type` in `group`
+fn renditions(
+    media: &[ExtXMedia],
+    media_type: MediaType,
+    group: Option<&str>,
+    base_url: &Url,
+) -> Vec<Rendition> {
+    let group = match group {
+        Some(g) => g,
+        None => return vec![],
+    };
+    media
+        .iter()
+        .filter(|m| m.media_type == media_type && m.group_id() == group)
+        .map(|m| Rendition {
+            name: m.name().to_string(),
+            language: m.language().map(|l| l.to_string()),
+            url: m.uri().map(|u| base_url.join(u).unwrap().to_string()),
+        })
+        .collect()
+}
+
+/// Find the audio rendition of `variant` in `language`
+pub fn audio_rendition<'a>(variant: &'a Variant, language: &str) -> Option<&'a Rendition> {
+    variant
+        .audio
+        .iter()
+        .find(|r| matches!(&r.language, Some(l) if same_language(l, language)))
+}
+
+/// Find the subtitle rendition of `variant` in `language`, or the first one if no language is
+/// given
+pub fn subtitle_rendition<'a>(
+    variant: &'a Variant,
+    language: Option<&str>,
+) -> Option<&'a Rendition> {
+    variant.subtitles.iter().find(|r| match language {
+        Some(language) => matches!(&r.language, Some(l) if same_language(l, language)),
+        None => true,
+    })
+}
+
+/// All audio languages of the `variants`. Streams with a single language don't have alternate
+/// audio, in which case an empty list is returned.
+pub fn audio_languages(variants: &[Variant]) -> Vec<String> {
+    let mut languages: Vec<String> = variants
+        .iter()
+        .flat_map(|v| v.audio.iter())
+        .filter_map(|r| r.language.to_owned())
+        .collect();
+    languages.sort();
+    languages.dedup();
+    if languages.len() > 1 {
+        languages
+    } else {
+        vec![]
+    }
+}
+
+/// Whether two RFC 5646 language tags are the same language. Only the primary language is
+/// compared and both 2 and 3 letter codes are accepted.
+fn same_language(a: &str, b: &str) -> bool {
+    primary_language(a) == primary_language(b)
+}
+
+fn primary_language(tag: &str) -> String {
+    let language = tag.split('-').next().unwrap_or_default().to_lowercase();
+    match LANGUAGE_CODES.iter().find(|(_, long)| *long == language) {
+        Some((short, _)) => short.to_string(),
+        None => language,
+    }
+}
+
+/// Pick a variant based on a `VariantPolicy`. Variants that exceed the maximum bandwidth or
+/// resolution are skipped, unless nothing is left, in which case the lowest variant is used.
+/// Preferred codecs and frame rates narrow down the selection when possible. Of the remaining
+/// variants the one with the highest (or lowest) bandwidth is picked.
+pub fn select_variant<'a>(variants: &'a [Variant], policy: &VariantPolicy) -> Option<&'a Variant> {
+    let mut candidates: Vec<&Variant> = variants
+        .iter()
+        .filter(|v| !matches!(policy.max_bandwidth, Some(max) if v.bandwidth > max))
+        .filter(|v| match (policy.max_resolution, v.resolution) {
+            (Some(max), Some((_, height))) => height <= max,
+            _ => true,
+        })
+        .collect();
+
+    if candidates.is_empty() {
+        return variants.first();
+    }
+
+    if let Some(codec) = &policy.codec {
+        let codec = codec.to_lowercase();
+        let matching = candidates
+            .iter()
+            .filter(|v| {
+                matches!(&v.codecs, Some(c) if c
+                    .to_lowercase()
+                    .split(',')
+                    .any(|c| c.trim().starts_with(&codec)))
+            })
+            .copied()
+            .collect::<Vec<&Variant>>();
+        if !matching.is_empty() {
+            candidates = matching;
+        }
+    }
+
+    if let Some(frame_rate) = policy.frame_rate {
+        let matching = candidates
+            .iter()
+            .filter(|v| matches!(v.frame_rate, Some(f) if (f - frame_rate).abs() < 0.01))
+            .copied()
+            .collect::<Vec<&Variant>>();
+        if !matching.is_empty() {
+            candidates = matching;
+        }
+    }
+
+    if policy.lowest {
+        candidates.first().copied()
+    } else {
+        candidates.last().copied()
+    }
+}
@@ -0,0 +1,97 @@
+use crate::{
+    config::VariantPolicy,
+    service::variant::{select_variant, Variant},
+};
+use std::{collections::VecDeque, time::Duration};
+
+// Step down after this many consecutive segments took longer to download than to play
+static DOWNSHIFT_AFTER: usize = 3;
+// Step up after this many consecutive segments downloaded in less than `UPSHIFT_RATIO` of their
+// duration
+static UPSHIFT_AFTER: usize = 10;
+static UPSHIFT_RATIO: f32 = 0.5;
+
+/// Keeps track of how long segment downloads take compared to the duration of the segments and
+/// switches to a lower variant when downloads fall behind real time. When throughput recovers,
+/// it steps back up, but never above the variant that was picked by the `VariantPolicy`.
+pub struct AdaptiveBitrate {
+    variants: Vec<Variant>,
+    ceiling: usize,
+    current: usize,
+    ratios: VecDeque<f32>,
+    enabled: bool,
+}
+
+impl AdaptiveBitrate {
+    pub fn new(variants: Vec<Variant>, policy: &VariantPolicy, enabled: bool) -> AdaptiveBitrate {
+        let ceiling = ceiling(&variants, policy);
+        AdaptiveBitrate {
+            variants,
+            ceiling,
+            current: ceiling,
+            ratios: VecDeque::new(),
+            enabled,
+        }
+    }
+
+    /// The variant that is currently being played
+    pub fn variant(&self) -> &Variant {
+        &self.variants[self.current]
+    }
+
+    /// Replace the variants (e.g. after the stream URL has been refreshed). If we had stepped
+    /// down before, we stay at the same bandwidth or lower.
+    pub fn update_variants(&mut self, variants: Vec<Variant>, policy: &VariantPolicy) {
+        let bandwidth = self.variant().bandwidth;
+        let stepped_down = self.current < self.ceiling;
+        self.ceiling = ceiling(&variants, policy);
+        self.current = if stepped_down {
+            variants
+                .iter()
+                .rposition(|v| v.bandwidth <= bandwidth)
+                .unwrap_or(0)
+                .min(self.ceiling)
+        } else {
+            self.ceiling
+        };
+        self.variants = variants;
+        self.ratios.clear();
+    }
+
+    /// Record how long it took to download a segment of `duration`. Returns the previous variant
+    /// if this caused a switch to another variant.
+    pub fn record(&mut self, download_time: Duration, duration: Duration) -> Option<Variant> {
+        if !self.enabled || duration.as_secs_f32() <= 0.0 {
+            return None;
+        }
+
+        self.ratios
+            .push_back(download_time.as_secs_f32() / duration.as_secs_f32());
+        if self.ratios.len() > UPSHIFT_AFTER {
+            self.ratios.pop_front();
+        }
+
+        let previous = self.current;
+        let slow = self.ratios.iter().rev().take(DOWNSHIFT_AFTER);
+        if self.current > 0 && slow.len() == DOWNSHIFT_AFTER && slow.clone().all(|r| *r > 1.0) {
+            self.current -= 1;
+        } else if self.current < self.ceiling
+            && self.ratios.len() == UPSHIFT_AFTER
+            && self.ratios.iter().all(|r| *r < UPSHIFT_RATIO)
+        {
+            self.current += 1;
+        } else {
+            return None;
+        }
+
+        self.ratios.clear();
+        Some(self.variants[previous].clone())
+    }
+}
+
+/// Index of the variant that is picked by `policy`
+fn ceiling(variants: &[Variant], policy: &VariantPolicy) -> usize {
+    select_variant(variants, policy)
+        .and_then(|selected| variants.iter().position(|v| std::ptr::eq(v, selected)))
+        .unwrap_or(0)
+}
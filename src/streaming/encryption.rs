@@ -0,0 +1,120 @@
+use bytes::Bytes;
+use hls_m3u8::{
+    tags::ExtXKey,
+    types::{EncryptionMethod, KeyFormat},
+};
+use openssl::symm::{Cipher, Crypter, Mode};
+use reqwest::Url;
+use std::collections::HashMap;
+
+// How many keys we keep around. Keys are normally rotated every now and then, so we only need the
+// last few.
+static MAX_KEYS: usize = 8;
+
+/// The AES-128 key and IV that a segment is encrypted with
+#[derive(Debug, Clone)]
+pub struct SegmentKey {
+    uri: String,
+    iv: [u8; 16],
+}
+
+impl SegmentKey {
+    /// Find the AES-128 key for a segment. Relative key URIs are made absolute using
+    /// `playlist_url`. If the IV isn't set explicitly, the parser derives it from the media
+    /// sequence number. Returns `None` for cleartext segments.
+    pub fn from_keys(keys: &[ExtXKey], playlist_url: &Url) -> Option<SegmentKey> {
+        let key = keys.iter().filter_map(|k| k.as_ref()).find(|k| {
+            k.method == EncryptionMethod::Aes128
+                && matches!(k.format, None | Some(KeyFormat::Identity))
+        })?;
+
+        Some(SegmentKey {
+            uri: playlist_url.join(key.uri()).unwrap().to_string(),
+            iv: key.iv.to_slice()?,
+        })
+    }
+}
+
+/// Fetches AES-128 keys, caches them and decrypts segments
+#[derive(Default)]
+pub struct Decrypter {
+    keys: HashMap<String, Bytes>,
+}
+
+impl Decrypter {
+    pub fn new() -> Decrypter {
+        Decrypter::default()
+    }
+
+    /// Start decrypting a segment that was encrypted with `key`
+    pub async fn start(&mut self, key: &SegmentKey) -> Result<SegmentDecrypter, String> {
+        let key_data = match self.keys.get(&key.uri) {
+            Some(k) => k.clone(),
+            None => {
+                let k = fetch_key(&key.uri).await?;
+                if self.keys.len() >= MAX_KEYS {
+                    self.keys.clear();
+                }
+                self.keys.insert(key.uri.to_owned(), k.clone());
+                k
+            }
+        };
+
+        Crypter::new(
+            Cipher::aes_128_cbc(),
+            Mode::Decrypt,
+            &key_data,
+            Some(&key.iv),
+        )
+        .map(|crypter| SegmentDecrypter { crypter })
+        .map_err(|e| format!("Unable to decrypt segment: {}", e))
+    }
+}
+
+/// Decrypts a single segment chunk by chunk, so it can be served while it's still downloading
+pub struct SegmentDecrypter {
+    crypter: Crypter,
+}
+
+impl SegmentDecrypter {
+    /// Decrypt the next chunk of the segment. Because AES works on blocks of 16 bytes, part of the
+    /// chunk may be held back until the next chunk (or the end of the segment) arrives.
+    pub fn update(&mut self, data: &[u8]) -> Result<Bytes, String> {
+        let mut out = vec![0; data.len() + Cipher::aes_128_cbc().block_size()];
+        let n = self
+            .crypter
+            .update(data, &mut out)
+            .map_err(|e| format!("Unable to decrypt segment: {}", e))?;
+        out.truncate(n);
+        Ok(Bytes::from(out))
+    }
+
+    /// Decrypt whatever is left at the end of the segment and check the padding
+    pub fn finalize(mut self) -> Result<Bytes, String> {
+        let mut out = vec![0; Cipher::aes_128_cbc().block_size()];
+        let n = self
+            .crypter
+            .finalize(&mut out)
+            .map_err(|e| format!("Unable to decrypt segment: {}", e))?;
+        out.truncate(n);
+        Ok(Bytes::from(out))
+    }
+}
+
+async fn fetch_key(uri: &str) -> Result<Bytes, String> {
+    debug!("Fetching key {}", uri);
+    let key = match crate::utils::get(uri, None, 5).await {
+        Ok(r) if r.status().is_success() => r.bytes().await.map_err(|e| e.to_string())?,
+        Ok(r) => return Err(format!("Unable to get key {}: {}", uri, r.status())),
+        Err(e) => return Err(format!("Unable to get key {}: {}", uri, e)),
+    };
+
+    if key.len() != 16 {
+        return Err(format!(
+            "Invalid key {}: expected 16 bytes, got {}",
+            uri,
+            key.len()
+        ));
+    }
+    Ok(key)
+}
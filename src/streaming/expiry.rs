@@ -0,0 +1,63 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use reqwest::Url;
+
+// How long a stream URL is used when it doesn't tell when it expires (2:45h)
+static URL_LIFETIME: i64 = 9900;
+
+// How many seconds before a stream URL expires it is refreshed
+static EXPIRY_MARGIN: i64 = 60;
+
+// Minimum number of seconds between two refreshes of a stream URL. This prevents hammering locast
+// when a fresh URL is rejected as well, or when a URL expires almost immediately.
+pub static MIN_REFRESH_INTERVAL: i64 = 30;
+
+// Query parameters that hold the expiry time of a signed URL
+static EXPIRY_PARAMETERS: [&str; 3] = ["expires", "expiry", "exp"];
+
+// Query parameters that hold a token with an `exp=` field, e.g.
+// `hdnts=st=1623456789~exp=1623460389~acl=/*~hmac=...`
+static TOKEN_PARAMETERS: [&str; 3] = ["hdnts", "hdnea", "token"];
+
+/// Find the expiry time that is embedded in a signed URL. Returns `None` if the URL isn't signed
+/// or if the expiry can't be parsed.
+pub fn url_expiry(url: &str) -> Option<DateTime<Utc>> {
+    let url = Url::parse(url).ok()?;
+    url.query_pairs().find_map(|(key, value)| {
+        let key = key.to_lowercase();
+        if EXPIRY_PARAMETERS.contains(&key.as_str()) {
+            timestamp(&value)
+        } else if TOKEN_PARAMETERS.contains(&key.as_str()) {
+            value
+                .split('~')
+                .find_map(|field| field.strip_prefix("exp="))
+                .and_then(timestamp)
+        } else {
+            None
+        }
+    })
+}
+
+/// When stream `url` should be refreshed: shortly before it expires, or after `URL_LIFETIME` if
+/// the URL doesn't tell when it expires.
+pub fn refresh_time(url: &str) -> DateTime<Utc> {
+    let now = Utc::now();
+    match url_expiry(url) {
+        Some(expiry) => {
+            debug!("Stream URL expires at {}", expiry);
+            (expiry - Duration::seconds(EXPIRY_MARGIN))
+                .max(now + Duration::seconds(MIN_REFRESH_INTERVAL))
+        }
+        None => now + Duration::seconds(URL_LIFETIME),
+    }
+}
+
+/// Parse a unix timestamp in seconds or milliseconds
+fn timestamp(value: &str) -> Option<DateTime<Utc>> {
+    let time = value.parse::<i64>().ok()?;
+    let seconds = if time > 100_000_000_000 {
+        time / 1000
+    } else {
+        time
+    };
+    Utc.timestamp_opt(seconds, 0).single()
+}
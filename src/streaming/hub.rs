@@ -0,0 +1,230 @@
+use super::StreamStatus;
+use crate::{
+    config::{Config, StartPolicy, VariantPolicy},
+    errors::AppError,
+    service::{station_provider::StationProvider, variant::Variant},
+};
+use bytes::Bytes;
+use futures::{lock::Mutex, stream, Stream, StreamExt};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+// How many chunks a subscriber can fall behind before it starts losing data. Segments are forwarded
+// in the chunks they are downloaded in, so a single segment easily takes up a few hundred of them.
+static HUB_CAPACITY: usize = 1024;
+
+/// A `Hub` owns a single upstream HLS session for a station and fans out the MPEG-TS bytes to
+/// all of its subscribers. The upstream session is stopped as soon as the last subscriber
+/// (and with that, the last reference to the `Hub`) goes away. When the upstream session ends on
+/// its own, the channel is closed, which ends the streams of all subscribers.
+pub struct Hub {
+    station_id: String,
+    stream_id: String,
+    sender: Arc<std::sync::Mutex<Option<broadcast::Sender<Bytes>>>>,
+    running: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+    status: Arc<std::sync::Mutex<StreamStatus>>,
+}
+
+impl Hub {
+    /// Start a new upstream session for station `id` using one of its `variants`. Where the
+    /// session starts in the playlist is determined by `start`.
+    fn start<T: 'static + StationProvider + Send + Sync + Clone>(
+        variants: Vec<Variant>,
+        service: T,
+        id: &str,
+        config: &Arc<Config>,
+        policy: VariantPolicy,
+        start: StartPolicy,
+    ) -> Hub {
+        let stream_id = Uuid::new_v4().to_string()[0..7].to_string();
+        let (sender, _) = broadcast::channel(HUB_CAPACITY);
+        let running = Arc::new(AtomicBool::new(true));
+
+        info!(
+            "Stream {} - starting upstream for station {}",
+            stream_id, id
+        );
+
+        let status = Arc::new(std::sync::Mutex::new(StreamStatus {
+            stream_id: stream_id.to_owned(),
+            ..Default::default()
+        }));
+        let upstream =
+            super::get_stream(variants, service, id, config, policy, start, status.clone());
+        let sender = Arc::new(std::sync::Mutex::new(Some(sender)));
+        // Dropped when the task ends, even if it panics or is aborted
+        let ended = Ended {
+            sender: sender.clone(),
+            running: running.clone(),
+        };
+        let task_stream_id = stream_id.clone();
+        let handle = tokio::spawn(async move {
+            futures::pin_mut!(upstream);
+            while let Some(chunk) = upstream.next().await {
+                // An error only means there are no subscribers at this very moment, which is
+                // fine, since the hub will be stopped when the last subscriber is gone.
+                if let Some(sender) = &*ended.sender.lock().unwrap() {
+                    let _ = sender.send(chunk);
+                }
+            }
+            info!("Stream {} - upstream ended", task_stream_id);
+        });
+
+        Hub {
+            station_id: id.to_owned(),
+            stream_id,
+            sender,
+            running,
+            handle,
+            status,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Marks a hub as ended when its upstream task is gone. Dropping the sender closes the channel, so
+/// subscribers don't keep waiting for chunks that will never come.
+struct Ended {
+    sender: Arc<std::sync::Mutex<Option<broadcast::Sender<Bytes>>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Drop for Ended {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Ok(mut sender) = self.sender.lock() {
+            sender.take();
+        }
+    }
+}
+
+impl Drop for Hub {
+    fn drop(&mut self) {
+        info!(
+            "Stream {} - no subscribers left, stopping upstream for station {}",
+            self.stream_id, self.station_id
+        );
+        self.handle.abort();
+    }
+}
+
+/// Registry of running `Hub`s, keyed by station id and profile. The registry only holds weak references, so
+/// hubs are owned by their subscribers.
+pub struct Hubs {
+    config: Arc<Config>,
+    hubs: Mutex<HashMap<String, Weak<Hub>>>,
+}
+
+impl Hubs {
+    pub fn new(config: Arc<Config>) -> Hubs {
+        Hubs {
+            config,
+            hubs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Subscribe to station `id` using variant `profile`. If there is no running hub for the
+    /// station and profile yet, a fresh stream URL is resolved through `service` and a new hub is
+    /// started at `start`. Subscribers that join a running hub always get the live stream. Besides
+    /// the stream, the status of the upstream session is returned.
+    pub async fn subscribe<T: 'static + StationProvider + Send + Sync + Clone>(
+        &self,
+        id: &str,
+        service: &T,
+        profile: Option<&str>,
+        policy: VariantPolicy,
+        start: StartPolicy,
+    ) -> Result<
+        (
+            impl Stream<Item = Bytes>,
+            Arc<std::sync::Mutex<StreamStatus>>,
+        ),
+        AppError,
+    > {
+        let key = format!("{}/{}", id, profile.unwrap_or("default"));
+        if let Some(hub) = self.running_hub(&key).await {
+            return Ok(subscription(hub));
+        }
+
+        // Resolve the variants without holding the lock, so tuning other stations isn't blocked
+        let variants = service.station_variants(id).await?;
+
+        let mut hubs = self.hubs.lock().await;
+        hubs.retain(|_, h| h.strong_count() > 0);
+
+        // Someone else might have started a hub while we were resolving the variants
+        let hub = match hubs.get(&key).and_then(|h| h.upgrade()) {
+            Some(hub) if hub.is_running() => hub,
+            _ => {
+                let hub = Arc::new(Hub::start(
+                    variants,
+                    service.clone(),
+                    id,
+                    &self.config,
+                    policy,
+                    start,
+                ));
+                hubs.insert(key, Arc::downgrade(&hub));
+                hub
+            }
+        };
+        Ok(subscription(hub))
+    }
+
+    async fn running_hub(&self, key: &str) -> Option<Arc<Hub>> {
+        let hubs = self.hubs.lock().await;
+        let hub = hubs.get(key)?.upgrade()?;
+        if hub.is_running() {
+            info!(
+                "Stream {} - joining running upstream for {}",
+                hub.stream_id, key
+            );
+            Some(hub)
+        } else {
+            None
+        }
+    }
+}
+
+/// Turn a subscription on a `Hub` into a stream of chunks. The stream holds on to the hub, so the
+/// hub stays alive for as long as the stream does. The stream ends when the upstream session does.
+fn subscription(
+    hub: Arc<Hub>,
+) -> (
+    impl Stream<Item = Bytes>,
+    Arc<std::sync::Mutex<StreamStatus>>,
+) {
+    let receiver = hub.sender.lock().unwrap().as_ref().map(|s| s.subscribe());
+    let status = hub.status.clone();
+    let stream = stream::unfold((receiver, hub), |(receiver, hub)| async move {
+        // The upstream session already ended
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(chunk) => return Some((chunk, (Some(receiver), hub))),
+                Err(RecvError::Lagged(n)) => {
+                    warn!(
+                        "Stream {} - subscriber lagged, skipped {} chunks",
+                        hub.stream_id, n
+                    )
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    (stream, status)
+}
@@ -0,0 +1,657 @@
+mod adaptive;
+mod encryption;
+mod expiry;
+pub mod hub;
+pub mod passthrough;
+mod prefetch;
+pub mod probe;
+pub mod sessions;
+pub mod subtitles;
+pub mod timeshift;
+pub mod transcode;
+pub mod tuners;
+pub mod udp;
+use self::{
+    adaptive::AdaptiveBitrate,
+    encryption::{Decrypter, SegmentDecrypter, SegmentKey},
+    expiry::{refresh_time, MIN_REFRESH_INTERVAL},
+    prefetch::{Download, DownloadError},
+    probe::probe,
+};
+use crate::{
+    config::{Config, StartPolicy, VariantPolicy},
+    service::{station_provider::StationProvider, variant::Variant},
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{future, stream, Stream, StreamExt};
+use reqwest::StatusCode;
+use reqwest::Url;
+use serde::Serialize;
+use std::str::FromStr;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+struct StreamState<T: StationProvider> {
+    segments: VecDeque<Segment>,
+    current: Option<Serving>,
+    url: String,
+    stream_id: String,
+    start_time: DateTime<Utc>,
+    seconds_served: f32,
+    service: T,
+    id: String,
+    refresh_at: DateTime<Utc>,
+    last_refresh: DateTime<Utc>,
+    read_ahead: usize,
+    policy: VariantPolicy,
+    start: StartPolicy,
+    abr: AdaptiveBitrate,
+    next_sequence: Option<usize>,
+    resync: bool,
+    live_edge: bool,
+    decrypter: Decrypter,
+    last_progress: Instant,
+    target_duration: Duration,
+    stall_timeout: Duration,
+    retries: u8,
+    max_retries: u8,
+    probe: Option<Vec<u8>>,
+    status: Arc<Mutex<StreamStatus>>,
+}
+
+/// What an upstream session is doing. This is updated after every segment that is served.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StreamStatus {
+    pub stream_id: String,
+    pub url: String,
+    pub seconds_served: f32,
+    pub refreshed: bool,
+    pub gaps: usize,
+    pub discontinuities: usize,
+    pub recoveries: usize,
+}
+
+// Maximum number of segments that are waiting to be served. If we have more than this, we're
+// so far behind that it's better to skip ahead.
+static MAX_QUEUED: usize = 30;
+
+// How many segments from the end of the playlist we start when resuming at the live edge
+static LIVE_EDGE_SEGMENTS: usize = 3;
+
+// Target duration that is assumed until the first media playlist has been read
+static DEFAULT_TARGET_DURATION: Duration = Duration::from_secs(10);
+
+// Size of an MPEG-TS packet
+static PACKET_SIZE: usize = 188;
+
+// MPEG-TS null packets (PID 0x1FFF) that are sent while recovering a stream, so clients don't
+// time out. 7 packets is what fits in a single UDP datagram, which most clients are used to.
+static NULL_PACKETS: usize = 7;
+
+// Maximum number of bytes of the first segment of a stream that are used to detect its codecs
+static PROBE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Follow the media playlist of one of the `variants` of station `id` and return a stream of
+/// MPEG-TS chunks. The variant is picked using `policy`, but a lower variant is used when
+/// downloads can't keep up. The first segment that is served is picked using `start`. The stream
+/// ends when the upstream can't be read anymore. Progress is reported through `status`.
+pub fn get_stream<T: 'static + StationProvider>(
+    variants: Vec<Variant>,
+    service: T,
+    id: &str,
+    config: &Arc<Config>,
+    policy: VariantPolicy,
+    start: StartPolicy,
+    status: Arc<Mutex<StreamStatus>>,
+) -> impl Stream<Item = Bytes> {
+    let stream_id = status.lock().unwrap().stream_id.to_owned();
+    let abr = AdaptiveBitrate::new(variants, &policy, !config.disable_adaptive_bitrate);
+
+    // Build helper struct
+    let state = StreamState {
+        segments: VecDeque::new(),
+        current: None,
+        url: abr.variant().url.to_owned(),
+        stream_id,
+        start_time: Utc::now(),
+        seconds_served: 0.0,
+        refresh_at: refresh_time(&abr.variant().url),
+        last_refresh: Utc::now(),
+        service,
+        id: id.to_owned(),
+        read_ahead: config.prefetch_segments as usize,
+        policy,
+        start,
+        abr,
+        next_sequence: None,
+        resync: false,
+        live_edge: false,
+        decrypter: Decrypter::new(),
+        last_progress: Instant::now(),
+        target_duration: DEFAULT_TARGET_DURATION,
+        stall_timeout: Duration::from_secs(config.stall_timeout),
+        retries: 0,
+        max_retries: config.stall_retries,
+        probe: Some(Vec::new()),
+        status,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            // Keep forwarding the segment that is currently being downloaded
+            if state.current.is_some() {
+                match serve_chunk(&mut state).await {
+                    Ok(Some(chunk)) => return Some((chunk, state)),
+                    Ok(None) => continue,
+                    Err(e) => return recover(state, &e).await,
+                }
+            }
+
+            // Refresh the URL shortly before it expires, or when access was denied
+            if Utc::now() >= state.refresh_at {
+                debug!("Stream {} -  URL expired: {}", state.stream_id, state.url);
+
+                // Grab a new URL for this stream. If this fails, we try to recover the stream.
+                match state.service.refresh_station_variants(&state.id).await {
+                    Ok(variants) => {
+                        state.abr.update_variants(variants, &state.policy);
+                        state.url = state.abr.variant().url.to_owned();
+                        debug!("Stream {} - New URL: {}", state.stream_id, state.url);
+                        state.refresh_at = refresh_time(&state.url);
+                        state.last_refresh = Utc::now();
+                        state.resync = true;
+                        state.status.lock().unwrap().refreshed = true;
+                    }
+                    Err(e) => {
+                        return recover(state, &format!("unable to refresh URL: {}", e)).await
+                    }
+                }
+            }
+
+            // Try fetching the latest m3u playlist. Intermittent errors may happen here,
+            // and when they do, we just skip updating until the next iteration.
+            match crate::utils::get(&state.url, None, 5).await {
+                // The URL expired or was revoked, so get a new one
+                Ok(r) if access_denied(r.status()) => {
+                    let reason = format!("playlist fetch failed: {}", r.status());
+                    if !refresh_now(&mut state, &reason) {
+                        return recover(state, &reason).await;
+                    }
+                    continue;
+                }
+                Ok(r) => match r.text().await {
+                    Ok(text) => Some(text),
+                    Err(e) => {
+                        return recover(state, &format!("unable to read playlist: {}", e)).await
+                    }
+                },
+                Err(e) => {
+                    warn!("Unable to get m3u data, skipping a fetch.. {}", e);
+                    None
+                }
+            }
+            .and_then(|m3u_data| {
+                // Decode the m3u into a MediaPlaylist.
+                match hls_m3u8::MediaPlaylist::from_str(m3u_data.as_str()) {
+                    Ok(p) => Some(p),
+                    Err(e) => {
+                        warn!("Unable to decode media playlist, skipping a fetch.. {}", e);
+                        None
+                    }
+                }
+            })
+            .map(|media_playlist| {
+                state.target_duration = media_playlist.target_duration;
+                let first_sequence = media_playlist.media_sequence;
+                let count = media_playlist.segments.iter().count();
+                if count == 0 {
+                    return Some(());
+                }
+                let last_sequence = first_sequence + count - 1;
+
+                // Figure out where to continue. Segments are tracked by their media sequence number,
+                // so segments we've already seen are never added again, even if their URL changed.
+                let next = match state.next_sequence {
+                    // After recovering, continue close to the live edge
+                    _ if state.live_edge => {
+                        let edge = (last_sequence + 1)
+                            .saturating_sub(LIVE_EDGE_SEGMENTS)
+                            .max(first_sequence);
+                        info!(
+                            "Stream {} - resuming at live edge (segment {})",
+                            state.stream_id, edge
+                        );
+                        if state.next_sequence.is_some() {
+                            state.status.lock().unwrap().discontinuities += 1;
+                        }
+                        edge
+                    }
+                    // A new stream starts where the start policy says so
+                    None => {
+                        let start = match state.start {
+                            StartPolicy::LiveEdge => last_sequence,
+                            StartPolicy::SegmentsBack(n) => {
+                                (last_sequence + 1).saturating_sub(n).max(first_sequence)
+                            }
+                            StartPolicy::Full => first_sequence,
+                        };
+                        info!(
+                            "Stream {} - starting at segment {} ({} to {} available)",
+                            state.stream_id, start, first_sequence, last_sequence
+                        );
+                        start
+                    }
+                    // After a URL refresh or variant switch the sequence numbers normally continue
+                    // where we left off. If they went back, the upstream restarted numbering and all
+                    // we can do is continue at the live edge.
+                    Some(next) if state.resync && next > last_sequence + 1 => {
+                        warn!(
+                            "Stream {} - discontinuity: media sequence restarted at {} (expected {}), continuing at {}",
+                            state.stream_id, first_sequence, next, last_sequence
+                        );
+                        state.status.lock().unwrap().discontinuities += 1;
+                        last_sequence
+                    }
+                    // Sequence numbers can also jump ahead after a URL refresh or variant switch.
+                    // Nothing was actually missed then, so don't count a gap and don't start at the
+                    // oldest segment, which could be minutes behind live.
+                    Some(next) if state.resync && next < first_sequence => {
+                        let edge = (last_sequence + 1)
+                            .saturating_sub(LIVE_EDGE_SEGMENTS)
+                            .max(first_sequence);
+                        warn!(
+                            "Stream {} - discontinuity: media sequence jumped to {} (expected {}), continuing at {}",
+                            state.stream_id, first_sequence, next, edge
+                        );
+                        state.status.lock().unwrap().discontinuities += 1;
+                        edge
+                    }
+                    Some(next) if next < first_sequence => {
+                        warn!(
+                            "Stream {} - gap: segments {} to {} expired before they could be fetched",
+                            state.stream_id,
+                            next,
+                            first_sequence - 1
+                        );
+                        state.status.lock().unwrap().gaps += first_sequence - next;
+                        first_sequence
+                    }
+                    Some(next) => next,
+                };
+                state.resync = false;
+                state.live_edge = false;
+
+                for (_i, ms) in media_playlist.segments {
+                    let sequence = ms.number();
+                    if sequence < next {
+                        continue;
+                    }
+
+                    if ms.has_discontinuity {
+                        info!(
+                            "Stream {} - discontinuity at segment {}",
+                            state.stream_id, sequence
+                        );
+                        state.status.lock().unwrap().discontinuities += 1;
+                    }
+
+                    let playlist_url = Url::parse(&state.url).unwrap();
+                    let absolute_uri = playlist_url.join(ms.uri()).unwrap().to_string();
+                    info!(
+                        "Stream {} - added segment {} {:?}",
+                        state.stream_id, sequence, &absolute_uri
+                    );
+                    state.segments.push_back(Segment {
+                        url: absolute_uri,
+                        sequence,
+                        duration: ms.duration.duration(),
+                        key: SegmentKey::from_keys(&ms.keys, &playlist_url),
+                        download: None,
+                    });
+                }
+                state.next_sequence = Some(next.max(last_sequence + 1));
+                Some(())
+            });
+
+            // If we're so far behind that segments pile up, skip ahead
+            if state.segments.len() > MAX_QUEUED {
+                let skip = state.segments.len() - MAX_QUEUED;
+                warn!(
+                    "Stream {} - falling behind, skipping {} segments",
+                    state.stream_id, skip
+                );
+                state.segments.drain(0..skip);
+                state.status.lock().unwrap().gaps += skip;
+            }
+
+            // If there are no segments to serve, we're either at the live edge, or the playlist
+            // isn't advancing. At the live edge, the next segment shows up within a target
+            // duration, so just poll the playlist again without sending anything. After that, keep
+            // the client busy for a while and if nothing shows up, try to recover.
+            if state.segments.is_empty() {
+                let idle = state.last_progress.elapsed();
+                if idle < state.target_duration {
+                    let until_refresh =
+                        (state.refresh_at - Utc::now()).to_std().unwrap_or_default();
+                    tokio::time::sleep(until_refresh.min(Duration::from_secs(1))).await;
+                    continue;
+                }
+                if idle < state.stall_timeout {
+                    debug!("Stream {} - no new segments, waiting..", state.stream_id);
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    return Some((null_packets(), state));
+                }
+                return recover(state, "playlist stopped advancing").await;
+            }
+
+            // Start downloading the next segment and up to `read_ahead` segments after that in the
+            // background. Downloads of segments further out are only started once earlier segments
+            // have been served, which keeps memory usage bounded.
+            for segment in state
+                .segments
+                .iter_mut()
+                .take(state.read_ahead + 1)
+                .filter(|s| s.download.is_none())
+            {
+                segment.download = Some(Download::start(&segment.url));
+            }
+
+            let (buffered, ready) = state
+                .segments
+                .iter()
+                .filter_map(|s| s.download.as_ref())
+                .fold((0, 0), |(b, r), d| (b + 1, r + d.is_done() as usize));
+            info!(
+                "Stream {} - read-ahead buffer: {} segments downloading, {} ready (depth: {})",
+                state.stream_id, buffered, ready, state.read_ahead
+            );
+
+            let first = &state.segments[0];
+
+            // Figure out how long we'll wait with serving the next segment. We do this
+            // because otherwise, we constantly loop through our segment list and
+            // make unnecessary calls to locast. This happens because serving a segment
+            // instantly returns, rather than waiting for the client to play
+            // the segment.
+            let runtime = Utc::now() - state.start_time;
+
+            // We want to be 50% of a segment behind. This is a nice trade-off
+            // between having enough time to fetch the next segment and calling
+            // locast too often.
+            let target_diff = 0.5 * first.duration.as_secs_f32();
+
+            let wait = if state.seconds_served > 0.0 {
+                state.seconds_served - target_diff - (runtime.num_milliseconds() as f32 / 1000.0)
+            } else {
+                0.0
+            };
+
+            info!(
+                "Serving {} ({} s) in {}s",
+                &first.url,
+                first.duration.as_secs_f32(),
+                wait
+            );
+
+            if wait > 0.0 {
+                tokio::time::sleep(tokio::time::Duration::from_secs_f32(wait)).await;
+            }
+
+            // Start serving the segment. Its bytes are forwarded as they come in.
+            let first = state.segments.pop_front().unwrap();
+            info!(
+                "Stream {} - playing: segment {} {:?}",
+                state.stream_id, first.sequence, first.url
+            );
+
+            // Decrypt encrypted segments, so clients always get plain MPEG-TS
+            let decrypter = match &first.key {
+                Some(key) => match state.decrypter.start(key).await {
+                    Ok(d) => Some(d),
+                    Err(e) => return recover(state, &e).await,
+                },
+                None => None,
+            };
+            state.current = Some(Serving {
+                segment: first,
+                decrypter,
+            });
+        }
+    })
+}
+
+/// Forward the next chunk of the segment that is being served. When the whole segment was
+/// received, the progress of the stream is updated and `None` is returned, so the next segment can
+/// be picked up.
+async fn serve_chunk<T: StationProvider>(
+    state: &mut StreamState<T>,
+) -> Result<Option<Bytes>, String> {
+    let current = state.current.as_mut().unwrap();
+    let sequence = current.segment.sequence;
+    let download = current.segment.download.as_mut().unwrap();
+    while let Some(chunk) = download.chunk().await {
+        let chunk = match (chunk, &mut current.decrypter) {
+            (Ok(c), Some(decrypter)) => decrypter.update(&c)?,
+            (Ok(c), None) => c,
+            // The URL expired or was revoked. Nothing of the segment has been served yet, so
+            // fetch it again after refreshing the URL.
+            (Err(DownloadError::Status(status)), _) if access_denied(status) => {
+                let reason = format!("segment fetch failed: {}", status);
+                if !refresh_now(state, &reason) {
+                    return Err(reason);
+                }
+                state.current = None;
+                state.segments.clear();
+                state.next_sequence = Some(sequence);
+                return Ok(None);
+            }
+            (Err(e), _) => return Err(format!("no bytes fetched: {}", e)),
+        };
+        // Decrypting may hold back a partial block, there's no point in sending nothing
+        if !chunk.is_empty() {
+            if let Some(buffer) = state.probe.as_mut().filter(|b| b.len() < PROBE_SIZE) {
+                buffer.extend_from_slice(&chunk);
+            }
+            return Ok(Some(chunk));
+        }
+    }
+
+    // The segment has been played, so we can forget about it
+    let Serving {
+        segment: first,
+        decrypter,
+    } = state.current.take().unwrap();
+    let download_time = first
+        .download
+        .as_ref()
+        .and_then(|d| d.elapsed())
+        .ok_or_else(|| "segment download was interrupted".to_owned())?;
+    let rest = match decrypter {
+        Some(decrypter) => decrypter.finalize()?,
+        None => Bytes::new(),
+    };
+
+    // Detect the codecs of the station, based on the first segment of the stream
+    if let Some(mut buffer) = state.probe.take() {
+        buffer.extend_from_slice(&rest);
+        match probe(&buffer) {
+            Some(info) => {
+                info!("Stream {} - detected {}", state.stream_id, info);
+                state.service.record_media_info(&state.id, info).await;
+            }
+            None => debug!("Stream {} - unable to detect codecs", state.stream_id),
+        }
+    }
+
+    state.seconds_served += first.duration.as_secs_f32();
+    state.last_progress = Instant::now();
+    state.retries = 0;
+    let duration = first.duration;
+
+    // Switch to another variant if downloads can't keep up with playback or if they have
+    // recovered. The segments of the new variant are picked up on the next iteration, starting
+    // right after the segment we just served.
+    if let Some(previous) = state.abr.record(download_time, duration) {
+        info!(
+            "Stream {} - switching variant from {} bit/s to {} bit/s (download took {:.2}s for a {:.2}s segment)",
+            state.stream_id,
+            previous.bandwidth,
+            state.abr.variant().bandwidth,
+            download_time.as_secs_f32(),
+            duration.as_secs_f32()
+        );
+        state.url = state.abr.variant().url.to_owned();
+        state.segments.clear();
+        state.next_sequence = Some(first.sequence + 1);
+        state.resync = true;
+    }
+
+    {
+        let mut status = state.status.lock().unwrap();
+        status.url = state.url.to_owned();
+        status.seconds_served = state.seconds_served;
+    }
+
+    Ok(if rest.is_empty() { None } else { Some(rest) })
+}
+
+/// Try to recover a stream that stalled or failed by resolving a fresh URL and resuming at the
+/// live edge. The client connection is kept open by sending null packets. The stream only ends
+/// when `max_retries` recoveries in a row didn't work.
+async fn recover<T: StationProvider>(
+    mut state: StreamState<T>,
+    reason: &str,
+) -> Option<(Bytes, StreamState<T>)> {
+    state.retries += 1;
+    if state.retries > state.max_retries {
+        warn!(
+            "Stream {} - {}, giving up after {} attempts. Stopping stream..",
+            state.stream_id, reason, state.max_retries
+        );
+        return None;
+    }
+    warn!(
+        "Stream {} - {}, recovering (attempt {}/{})",
+        state.stream_id, reason, state.retries, state.max_retries
+    );
+    state.status.lock().unwrap().recoveries += 1;
+
+    // Back off a bit more with every attempt
+    tokio::time::sleep(tokio::time::Duration::from_secs(state.retries as u64)).await;
+
+    match state.service.refresh_station_variants(&state.id).await {
+        Ok(variants) => {
+            state.abr.update_variants(variants, &state.policy);
+            state.url = state.abr.variant().url.to_owned();
+            debug!("Stream {} - New URL: {}", state.stream_id, state.url);
+            state.refresh_at = refresh_time(&state.url);
+            state.last_refresh = Utc::now();
+        }
+        Err(e) => warn!("Stream {} - unable to resolve URL: {}", state.stream_id, e),
+    }
+
+    state.segments.clear();
+    state.current = None;
+    state.live_edge = true;
+    state.last_progress = Instant::now();
+    Some((null_packets(), state))
+}
+
+/// Whether the upstream refused access, which normally means that the stream URL expired
+fn access_denied(status: StatusCode) -> bool {
+    matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+}
+
+/// Make sure the stream URL is refreshed before anything else is fetched. Returns `false` if the
+/// URL was refreshed very recently, in which case a refresh won't help and the stream has to be
+/// recovered instead.
+fn refresh_now<T: StationProvider>(state: &mut StreamState<T>, reason: &str) -> bool {
+    if (Utc::now() - state.last_refresh).num_seconds() < MIN_REFRESH_INTERVAL {
+        return false;
+    }
+    warn!("Stream {} - {}, refreshing URL", state.stream_id, reason);
+    state.refresh_at = Utc::now();
+    state.resync = true;
+    true
+}
+
+/// MPEG-TS null packets that clients will ignore
+fn null_packets() -> Bytes {
+    let mut packets = Vec::with_capacity(NULL_PACKETS * 188);
+    for _ in 0..NULL_PACKETS {
+        packets.extend_from_slice(&[0x47, 0x1f, 0xff, 0x10]);
+        packets.resize(packets.len() + 184, 0xff);
+    }
+    Bytes::from(packets)
+}
+
+/// Make sure `pending` starts with an MPEG-TS packet. Bytes before the first sync byte are dropped,
+/// which only happens when a segment got cut off.
+fn align(pending: &mut Vec<u8>) {
+    if pending.is_empty() || pending[0] == 0x47 {
+        return;
+    }
+    let start = (0..pending.len())
+        .find(|&i| {
+            pending[i] == 0x47 && pending.get(i + PACKET_SIZE).copied().unwrap_or(0x47) == 0x47
+        })
+        .unwrap_or(pending.len());
+    pending.drain(..start);
+}
+
+#[derive(Debug)]
+struct Segment {
+    url: String,
+    sequence: usize,
+    duration: std::time::Duration,
+    key: Option<SegmentKey>,
+    download: Option<Download>,
+}
+
+/// The segment that is being forwarded to the client
+struct Serving {
+    segment: Segment,
+    decrypter: Option<SegmentDecrypter>,
+}
+
+/// End `stream` after `duration` seconds of video have been served. Progress is taken from
+/// `status`, which is updated after every segment.
+pub fn limit_duration<S: Stream<Item = Bytes>>(
+    stream: S,
+    status: Arc<Mutex<StreamStatus>>,
+    duration: u64,
+) -> impl Stream<Item = Bytes> {
+    let start = status.lock().unwrap().seconds_served;
+    stream.take_while(move |_| {
+        let served = status.lock().unwrap().seconds_served - start;
+        future::ready(served < duration as f32)
+    })
+}
+
+/// Fetch a playlist or another text document. Returns `None` if it can't be fetched.
+async fn fetch_text(url: &str) -> Option<String> {
+    match crate::utils::get(url, None, 5).await {
+        Ok(r) if r.status().is_success() => r.text().await.ok(),
+        _ => None,
+    }
+}
+
+/// Turn the body of a `Response` into a stream of chunks, as they come in. The stream ends when
+/// the body has been read, or when reading the body fails.
+fn body_stream(response: reqwest::Response) -> impl Stream<Item = Bytes> {
+    stream::unfold(Some(response), |response| async move {
+        let mut response = response?;
+        match response.chunk().await {
+            Ok(Some(chunk)) => Some((chunk, Some(response))),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Unable to read response body: {}", e);
+                None
+            }
+        }
+    })
+}
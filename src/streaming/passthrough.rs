@@ -0,0 +1,411 @@
+use super::{body_stream, expiry::refresh_time, fetch_text};
+use crate::{
+    config::VariantPolicy,
+    errors::AppError,
+    service::{
+        station_provider::StationProvider,
+        variant::{audio_rendition, select_variant, subtitle_rendition},
+    },
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{lock::Mutex, Stream};
+use reqwest::Url;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use uuid::Uuid;
+
+// How many segment keys we remember per station. Clients only request segments that are in the
+// latest playlist, so this only has to be a bit bigger than a playlist.
+static MAX_SEGMENTS: usize = 50;
+
+/// Serves HLS media playlists for stations with segment URIs that point back at locast2tuner,
+/// and proxies segment requests to locast. This keeps locast tokens and hostnames hidden from
+/// clients.
+#[derive(Default)]
+pub struct HlsProxy {
+    sessions: Mutex<HashMap<String, HlsSession>>,
+}
+
+/// Which media playlist of a station is served
+#[derive(Debug, Clone, Copy)]
+pub enum Track<'a> {
+    /// The variant stream itself
+    Main,
+    /// The audio rendition in a language
+    Audio(&'a str),
+    /// The subtitle rendition in a language
+    Subtitles(&'a str),
+}
+
+struct HlsSession {
+    station_id: String,
+    url: String,
+    refresh_at: DateTime<Utc>,
+    segments: VecDeque<(String, String)>, // (key, upstream URL)
+}
+
+impl HlsProxy {
+    pub fn new() -> HlsProxy {
+        HlsProxy::default()
+    }
+
+    /// Fetch the media playlist of `track` of station `id` using variant `profile` and rewrite
+    /// all segment URIs to `{base_url}/{key}.ts` (or `.vtt` for subtitles). Encryption keys are
+    /// rewritten to `{base_url}/{key}.key`.
+    pub async fn playlist<T: StationProvider>(
+        &self,
+        id: &str,
+        service: &T,
+        profile: Option<&str>,
+        policy: &VariantPolicy,
+        track: Track<'_>,
+        base_url: &str,
+    ) -> Result<String, AppError> {
+        let session_key = format!("{}/{}/{}", id, profile.unwrap_or("default"), track);
+
+        // Use the cached media playlist URL, unless it's about to expire
+        let url = match self.sessions.lock().await.get(&session_key) {
+            Some(s) if Utc::now() < s.refresh_at => Some(s.url.to_owned()),
+            _ => None,
+        };
+        let url = match url {
+            Some(u) => u,
+            None => {
+                self.resolve(id, service, &session_key, policy, track, false)
+                    .await?
+            }
+        };
+
+        let (url, m3u_data) = match fetch_text(&url).await {
+            Some(d) => (url, d),
+            None => {
+                // The URL might not be valid anymore, so try again with a fresh one
+                warn!("Unable to get m3u data for {}, resolving a new URL..", id);
+                let url = self
+                    .resolve(id, service, &session_key, policy, track, true)
+                    .await?;
+                let m3u_data = fetch_text(&url).await.ok_or(AppError::BadGateway)?;
+                (url, m3u_data)
+            }
+        };
+
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get_mut(&session_key).unwrap();
+        let playlist_url = Url::parse(&url).unwrap();
+        Ok(session.rewrite(&m3u_data, &playlist_url, track, base_url))
+    }
+
+    /// Resolve the media playlist URL of `track` of station `id`. If `fresh` is set, a recently
+    /// resolved URL isn't reused. Segment keys that were handed out before stay valid.
+    async fn resolve<T: StationProvider>(
+        &self,
+        id: &str,
+        service: &T,
+        session_key: &str,
+        policy: &VariantPolicy,
+        track: Track<'_>,
+        fresh: bool,
+    ) -> Result<String, AppError> {
+        let variants = if fresh {
+            service.refresh_station_variants(id).await?
+        } else {
+            service.station_variants(id).await?
+        };
+        let variant = select_variant(&variants, policy).ok_or(AppError::NotFound)?;
+        let url = match track {
+            Track::Main => Some(variant.url.to_owned()),
+            Track::Audio(language) => {
+                audio_rendition(variant, language).and_then(|r| r.url.to_owned())
+            }
+            Track::Subtitles(language) => {
+                subtitle_rendition(variant, Some(language)).and_then(|r| r.url.to_owned())
+            }
+        }
+        .ok_or_else(|| {
+            warn!("Station {} doesn't have a separate {} track", id, track);
+            AppError::NotFound
+        })?;
+        debug!("HLS passthrough for {} uses {}", id, url);
+
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions
+            .entry(session_key.to_owned())
+            .or_insert_with(|| HlsSession {
+                station_id: id.to_owned(),
+                url: url.to_owned(),
+                refresh_at: refresh_time(&url),
+                segments: VecDeque::new(),
+            });
+        session.url = url.to_owned();
+        session.refresh_at = refresh_time(&url);
+        Ok(url)
+    }
+
+    /// Proxy the segment with `key` for station `id`
+    pub async fn segment(
+        &self,
+        id: &str,
+        key: &str,
+    ) -> Result<impl Stream<Item = Bytes>, AppError> {
+        let url = self
+            .sessions
+            .lock()
+            .await
+            .values()
+            .filter(|s| s.station_id == id)
+            .flat_map(|s| s.segments.iter())
+            .find(|(k, _)| k == key)
+            .map(|(_, u)| u.to_owned())
+            .ok_or(AppError::NotFound)?;
+
+        match crate::utils::get(&url, None, 10).await {
+            Ok(r) if r.status().is_success() => Ok(body_stream(r)),
+            Ok(r) => {
+                warn!("Unable to get segment {}: {}", url, r.status());
+                Err(AppError::BadGateway)
+            }
+            Err(e) => {
+                warn!("Unable to get segment {}: {}", url, e);
+                Err(AppError::BadGateway)
+            }
+        }
+    }
+}
+
+impl Track<'_> {
+    fn extension(&self) -> &str {
+        match self {
+            Track::Subtitles(_) => "vtt",
+            _ => "ts",
+        }
+    }
+}
+
+impl fmt::Display for Track<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Track::Main => write!(f, "main"),
+            Track::Audio(language) => write!(f, "audio-{}", language),
+            Track::Subtitles(language) => write!(f, "subtitles-{}", language),
+        }
+    }
+}
+
+/// Build a master playlist for station `id` that combines the variant picked by `policy` with
+/// its subtitle renditions, so HLS clients can show subtitles. `playlist_url` is the URL of the
+/// media playlist that locast2tuner serves for the variant. Subtitle playlists are served at the
+/// same URL, with a `subtitles` parameter.
+pub async fn master_playlist<T: StationProvider>(
+    id: &str,
+    service: &T,
+    policy: &VariantPolicy,
+    playlist_url: &Url,
+) -> Result<String, AppError> {
+    let variants = service.station_variants(id).await?;
+    let variant = select_variant(&variants, policy).ok_or(AppError::NotFound)?;
+
+    let mut playlist = String::from("#EXTM3U\n");
+    let mut stream_inf = format!("#EXT-X-STREAM-INF:BANDWIDTH={}", variant.bandwidth);
+    if let Some((width, height)) = variant.resolution {
+        stream_inf.push_str(&format!(",RESOLUTION={}x{}", width, height));
+    }
+    if let Some(codecs) = &variant.codecs {
+        stream_inf.push_str(&format!(",CODECS=\"{}\"", codecs));
+    }
+
+    // Subtitle playlists are selected by language, so renditions without one can't be served
+    let mut has_subtitles = false;
+    for rendition in variant.subtitles.iter().filter(|r| r.url.is_some()) {
+        let language = match &rendition.language {
+            Some(l) => l,
+            None => continue,
+        };
+        let mut uri = playlist_url.clone();
+        uri.query_pairs_mut().append_pair("subtitles", language);
+        playlist.push_str(&format!(
+            "#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"{}\",LANGUAGE=\"{}\",AUTOSELECT=YES,URI=\"{}\"\n",
+            rendition.name.replace('"', "'"),
+            language,
+            uri
+        ));
+        has_subtitles = true;
+    }
+    if has_subtitles {
+        stream_inf.push_str(",SUBTITLES=\"subs\"");
+    }
+
+    playlist.push_str(&format!("{}\n{}\n", stream_inf, playlist_url));
+    Ok(playlist)
+}
+
+impl HlsSession {
+    /// Rewrite all URIs in media playlist `m3u_data` to point at `base_url`. This includes the
+    /// `URI` attributes of tags like `#EXT-X-KEY` and `#EXT-X-MAP`. Relative URIs are resolved
+    /// against `playlist_url` first.
+    fn rewrite(
+        &mut self,
+        m3u_data: &str,
+        playlist_url: &Url,
+        track: Track,
+        base_url: &str,
+    ) -> String {
+        let mut rewritten = String::new();
+        for line in m3u_data.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                let extension = if line.starts_with("#EXT-X-KEY:") {
+                    "key"
+                } else {
+                    track.extension()
+                };
+                rewritten.push_str(&rewrite_uri_attribute(line, |uri| {
+                    let absolute_uri = playlist_url.join(uri).unwrap().to_string();
+                    format!("{}/{}.{}", base_url, self.key(&absolute_uri), extension)
+                }));
+            } else {
+                let absolute_uri = playlist_url.join(line).unwrap().to_string();
+                let key = self.key(&absolute_uri);
+                rewritten.push_str(&format!("{}/{}.{}", base_url, key, track.extension()));
+            }
+            rewritten.push('\n');
+        }
+        rewritten
+    }
+
+    /// Return the key for an upstream segment URL and remember it
+    fn key(&mut self, url: &str) -> String {
+        let key = Uuid::new_v5(&Uuid::NAMESPACE_URL, url.as_bytes())
+            .to_simple()
+            .to_string();
+        if !self.segments.iter().any(|(k, _)| k == &key) {
+            self.segments.push_back((key.clone(), url.to_owned()));
+            if self.segments.len() > MAX_SEGMENTS {
+                self.segments.pop_front();
+            }
+        }
+        key
+    }
+}
+
+/// Replace the value of the `URI` attribute of tag `line` with the result of `rewrite`. Lines
+/// without a `URI` attribute are returned as they are.
+fn rewrite_uri_attribute(line: &str, rewrite: impl FnOnce(&str) -> String) -> String {
+    let start = match line.find(":URI=\"").or_else(|| line.find(",URI=\"")) {
+        Some(i) => i + 6,
+        None => return line.to_owned(),
+    };
+    let end = match line[start..].find('"') {
+        Some(i) => start + i,
+        None => return line.to_owned(),
+    };
+    format!(
+        "{}{}{}",
+        &line[..start],
+        rewrite(&line[start..end]),
+        &line[end..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> HlsSession {
+        HlsSession {
+            station_id: "1234".to_owned(),
+            url: "https://cdn.example.com/live/abc/index.m3u8?token=secret".to_owned(),
+            refresh_at: Utc::now(),
+            segments: VecDeque::new(),
+        }
+    }
+
+    /// The upstream URL that `uri` in a rewritten playlist proxies to
+    fn upstream<'a>(session: &'a HlsSession, uri: &str) -> &'a str {
+        let key = uri
+            .trim_start_matches("http://tuner/watch/1234/")
+            .split('.')
+            .next()
+            .unwrap();
+        session
+            .segments
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, u)| u.as_str())
+            .unwrap()
+    }
+
+    #[test]
+    fn rewrite_encrypted_playlist() {
+        let mut session = session();
+        let playlist_url = Url::parse(&session.url).unwrap();
+        let playlist = "#EXTM3U\n\
+            #EXT-X-TARGETDURATION:6\n\
+            #EXT-X-MEDIA-SEQUENCE:100\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"keys/1.key?token=secret\",IV=0x0000000000000000000000000000000a\n\
+            #EXT-X-MAP:URI=\"https://cdn.example.com/init.mp4\"\n\
+            #EXTINF:6.0,\n\
+            segment100.ts?token=secret\n";
+
+        let rewritten = session.rewrite(
+            playlist,
+            &playlist_url,
+            Track::Main,
+            "http://tuner/watch/1234",
+        );
+        assert!(!rewritten.contains("cdn.example.com"));
+        assert!(!rewritten.contains("secret"));
+
+        let lines: Vec<&str> = rewritten.lines().collect();
+        assert_eq!(
+            lines[..3],
+            [
+                "#EXTM3U",
+                "#EXT-X-TARGETDURATION:6",
+                "#EXT-X-MEDIA-SEQUENCE:100"
+            ]
+        );
+
+        let key = lines[3]
+            .strip_prefix("#EXT-X-KEY:METHOD=AES-128,URI=\"")
+            .unwrap();
+        let (key_uri, rest) = key.split_at(key.find('"').unwrap());
+        assert!(key_uri.ends_with(".key"));
+        assert_eq!(rest, "\",IV=0x0000000000000000000000000000000a");
+        assert_eq!(
+            upstream(&session, key_uri),
+            "https://cdn.example.com/live/abc/keys/1.key?token=secret"
+        );
+
+        let map_uri = lines[4]
+            .strip_prefix("#EXT-X-MAP:URI=\"")
+            .and_then(|u| u.strip_suffix('"'))
+            .unwrap();
+        assert_eq!(
+            upstream(&session, map_uri),
+            "https://cdn.example.com/init.mp4"
+        );
+
+        assert_eq!(lines[5], "#EXTINF:6.0,");
+        assert!(lines[6].ends_with(".ts"));
+        assert_eq!(
+            upstream(&session, lines[6]),
+            "https://cdn.example.com/live/abc/segment100.ts?token=secret"
+        );
+    }
+
+    #[test]
+    fn keep_tags_without_uri() {
+        assert_eq!(
+            rewrite_uri_attribute("#EXT-X-KEY:METHOD=NONE", |_| unreachable!()),
+            "#EXT-X-KEY:METHOD=NONE"
+        );
+        assert_eq!(
+            rewrite_uri_attribute(
+                "#EXT-X-KEY:METHOD=AES-128,KEYFORMATURI=\"x\"",
+                |_| unreachable!()
+            ),
+            "#EXT-X-KEY:METHOD=AES-128,KEYFORMATURI=\"x\""
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use bytes::Bytes;
+use reqwest::StatusCode;
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+/// A segment download that runs in the background. Chunks are handed out as soon as they arrive,
+/// so a segment can be served while it's still downloading. Dropping a `Download` aborts it, so
+/// segments that are dropped from the stream state don't keep downloading.
+#[derive(Debug)]
+pub struct Download {
+    handle: JoinHandle<()>,
+    chunks: mpsc::UnboundedReceiver<Result<Bytes, DownloadError>>,
+    done: Arc<AtomicBool>,
+    elapsed: Arc<Mutex<Option<Duration>>>,
+}
+
+impl Download {
+    /// Start downloading `url` in the background
+    pub fn start(url: &str) -> Download {
+        let url = url.to_owned();
+        let (sender, chunks) = mpsc::unbounded_channel();
+        let done = Arc::new(AtomicBool::new(false));
+        let elapsed = Arc::new(Mutex::new(None));
+        let task_done = done.clone();
+        let task_elapsed = elapsed.clone();
+        let handle = tokio::spawn(async move {
+            let start = Instant::now();
+            match crate::utils::get(&url, None, 10).await {
+                Ok(r) if !r.status().is_success() => {
+                    let _ = sender.send(Err(DownloadError::Status(r.status())));
+                }
+                Ok(mut r) => loop {
+                    match r.chunk().await {
+                        Ok(Some(chunk)) => {
+                            let _ = sender.send(Ok(chunk));
+                        }
+                        Ok(None) => {
+                            *task_elapsed.lock().unwrap() = Some(start.elapsed());
+                            break;
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(DownloadError::Failed(e.to_string())));
+                            break;
+                        }
+                    }
+                },
+                Err(e) => {
+                    let _ = sender.send(Err(DownloadError::Failed(e.to_string())));
+                }
+            };
+            task_done.store(true, Ordering::SeqCst);
+        });
+
+        Download {
+            handle,
+            chunks,
+            done,
+            elapsed,
+        }
+    }
+
+    /// Whether the download has finished (either successfully or not)
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    /// Wait for the next chunk of the segment. Returns `None` when the whole segment was received.
+    pub async fn chunk(&mut self) -> Option<Result<Bytes, DownloadError>> {
+        self.chunks.recv().await
+    }
+
+    /// How long it took to download the whole segment, once it's complete
+    pub fn elapsed(&self) -> Option<Duration> {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+/// Why a segment couldn't be downloaded
+#[derive(Debug)]
+pub enum DownloadError {
+    /// The server responded with an error status
+    Status(StatusCode),
+    /// The request or reading the response failed
+    Failed(String),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DownloadError::Status(status) => write!(f, "{}", status),
+            DownloadError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
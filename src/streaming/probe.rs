@@ -0,0 +1,992 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Size of an MPEG-TS packet
+static PACKET_SIZE: usize = 188;
+
+// How many bytes of an elementary stream are collected to look for its headers
+static MAX_ES_DATA: usize = 512 * 1024;
+
+// H.264 profiles that have chroma format and bit depth fields in their SPS
+static HIGH_PROFILES: [u8; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+
+// H.264 sample aspect ratios by `aspect_ratio_idc`
+static SAMPLE_ASPECT_RATIOS: [(u32, u32); 17] = [
+    (0, 0),
+    (1, 1),
+    (12, 11),
+    (10, 11),
+    (16, 11),
+    (40, 33),
+    (24, 11),
+    (20, 11),
+    (32, 11),
+    (80, 33),
+    (18, 11),
+    (15, 11),
+    (64, 33),
+    (160, 99),
+    (4, 3),
+    (3, 2),
+    (2, 1),
+];
+
+// MPEG-2 video frame rates by `frame_rate_code`
+static MPEG2_FRAME_RATES: [f32; 9] = [0.0, 23.976, 24.0, 25.0, 29.97, 30.0, 50.0, 59.94, 60.0];
+
+// AC-3 number of full bandwidth channels by `acmod`
+static AC3_CHANNELS: [u8; 8] = [2, 1, 2, 3, 3, 4, 4, 5];
+
+// AAC sample rates by `sampling_frequency_index`
+static AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// The video and audio a station carries, as found in its MPEG-TS stream
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MediaInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioInfo>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct VideoInfo {
+    pub codec: VideoCodec,
+    pub width: u32,
+    pub height: u32,
+    /// Display aspect ratio (width / height)
+    pub aspect: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AudioInfo {
+    pub codec: AudioCodec,
+    /// Number of full bandwidth channels
+    pub channels: u8,
+    /// Whether there's a low frequency effects (subwoofer) channel
+    pub lfe: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum VideoCodec {
+    Mpeg2,
+    H264 {
+        profile: u8,
+        constraints: u8,
+        level: u8,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum AudioCodec {
+    Mpeg,
+    Aac { object_type: u8 },
+    Ac3,
+    Eac3,
+}
+
+impl MediaInfo {
+    /// The `Content-Type` of the stream, including its codecs. Returns `None` if nothing is known
+    /// about the codecs.
+    pub fn content_type(&self) -> Option<String> {
+        let codecs: Vec<String> = self
+            .video
+            .iter()
+            .map(|v| v.codec.codecs())
+            .chain(self.audio.iter().map(|a| a.codec.codecs()))
+            .collect();
+        if codecs.is_empty() {
+            None
+        } else {
+            Some(format!("video/mpeg; codecs='{}'", codecs.join(",")))
+        }
+    }
+}
+
+impl fmt::Display for MediaInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(video) = &self.video {
+            let mut part = format!("{} {}x{}", video.codec.name(), video.width, video.height);
+            if let Some(rate) = video.frame_rate {
+                part.push_str(&format!(" {} fps", rate));
+            }
+            parts.push(part);
+        }
+        if let Some(audio) = &self.audio {
+            parts.push(format!("{} {}", audio.codec.name(), audio.layout()));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl VideoCodec {
+    /// Name of the codec, as used by HDHomeRun
+    pub fn name(&self) -> &'static str {
+        match self {
+            VideoCodec::Mpeg2 => "MPEG2",
+            VideoCodec::H264 { .. } => "H264",
+        }
+    }
+
+    /// Codec as used in the `codecs` parameter of a MIME type (RFC 6381)
+    pub fn codecs(&self) -> String {
+        match self {
+            VideoCodec::Mpeg2 => "mp4v.61".to_owned(),
+            VideoCodec::H264 {
+                profile,
+                constraints,
+                level,
+            } => format!("avc1.{:02X}{:02X}{:02X}", profile, constraints, level),
+        }
+    }
+}
+
+impl VideoInfo {
+    /// Aspect ratio as used in XMLTV
+    pub fn aspect_ratio(&self) -> &'static str {
+        if self.aspect > 1.5 {
+            "16:9"
+        } else {
+            "4:3"
+        }
+    }
+
+    pub fn is_hd(&self) -> bool {
+        self.height >= 720
+    }
+
+    /// Either `HDTV` or `SD`, as used in XMLTV
+    pub fn quality(&self) -> &'static str {
+        if self.is_hd() {
+            "HDTV"
+        } else {
+            "SD"
+        }
+    }
+}
+
+impl AudioCodec {
+    /// Name of the codec, as used by HDHomeRun
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioCodec::Mpeg => "MPEG",
+            AudioCodec::Aac { .. } => "AAC",
+            AudioCodec::Ac3 => "AC3",
+            AudioCodec::Eac3 => "EAC3",
+        }
+    }
+
+    /// Codec as used in the `codecs` parameter of a MIME type (RFC 6381)
+    pub fn codecs(&self) -> String {
+        match self {
+            AudioCodec::Mpeg => "mp4a.6B".to_owned(),
+            AudioCodec::Aac { object_type } => format!("mp4a.40.{}", object_type),
+            AudioCodec::Ac3 => "ac-3".to_owned(),
+            AudioCodec::Eac3 => "ec-3".to_owned(),
+        }
+    }
+}
+
+impl AudioInfo {
+    /// Channel layout, e.g. `stereo` or `5.1`
+    pub fn layout(&self) -> String {
+        match (self.channels, self.lfe) {
+            (1, false) => "mono".to_owned(),
+            (2, false) => "stereo".to_owned(),
+            (channels, lfe) => format!("{}.{}", channels, lfe as u8),
+        }
+    }
+
+    /// Value of the XMLTV `<stereo>` element
+    pub fn stereo(&self) -> &'static str {
+        match (self.codec, self.channels) {
+            (_, 1) => "mono",
+            (_, 2) => "stereo",
+            (AudioCodec::Ac3, _) | (AudioCodec::Eac3, _) => "dolby digital",
+            _ => "surround",
+        }
+    }
+}
+
+/// Look at the start of an MPEG-TS stream and figure out what video and audio it carries. The
+/// PAT and PMT tell which elementary streams there are, after which the headers of the first video
+/// and audio stream are parsed. Returns `None` if neither could be parsed.
+pub fn probe(data: &[u8]) -> Option<MediaInfo> {
+    let packets = packets(data);
+    let pmt_pid = packets
+        .iter()
+        .filter(|p| p.pid == 0 && p.start)
+        .find_map(|p| parse_pat(p.payload))?;
+    let streams = packets
+        .iter()
+        .filter(|p| p.pid == pmt_pid && p.start)
+        .find_map(|p| parse_pmt(p.payload))?;
+
+    let video = streams
+        .iter()
+        .find(|(_, t)| matches!(t, StreamType::Mpeg2Video | StreamType::H264))
+        .and_then(|(pid, stream_type)| {
+            let data = es_data(&packets, *pid);
+            match stream_type {
+                StreamType::Mpeg2Video => parse_mpeg2_video(&data),
+                _ => parse_h264(&data),
+            }
+        });
+    let audio = streams
+        .iter()
+        .find(|(_, t)| {
+            matches!(
+                t,
+                StreamType::MpegAudio | StreamType::Aac | StreamType::Ac3 | StreamType::Eac3
+            )
+        })
+        .and_then(|(pid, stream_type)| {
+            let data = es_data(&packets, *pid);
+            match stream_type {
+                StreamType::MpegAudio => parse_mpeg_audio(&data),
+                StreamType::Aac => parse_adts(&data),
+                _ => parse_ac3(&data),
+            }
+        });
+
+    if video.is_none() && audio.is_none() {
+        return None;
+    }
+    Some(MediaInfo { video, audio })
+}
+
+/// Elementary stream types we know how to parse
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StreamType {
+    Mpeg2Video,
+    H264,
+    MpegAudio,
+    Aac,
+    Ac3,
+    Eac3,
+}
+
+struct Packet<'a> {
+    pid: u16,
+    /// Whether a PES packet or PSI section starts in this packet
+    start: bool,
+    payload: &'a [u8],
+}
+
+/// Split `data` into MPEG-TS packets. Packets without a payload are left out.
+fn packets(data: &[u8]) -> Vec<Packet<'_>> {
+    // Find the first sync byte that is followed by another one a packet later
+    let offset = match (0..PACKET_SIZE).find(|&i| {
+        data.get(i) == Some(&0x47) && data.get(i + PACKET_SIZE).copied().unwrap_or(0x47) == 0x47
+    }) {
+        Some(o) => o,
+        None => return Vec::new(),
+    };
+
+    data[offset..]
+        .chunks_exact(PACKET_SIZE)
+        .filter(|p| p[0] == 0x47)
+        .filter_map(|p| {
+            let adaptation_field_control = (p[3] >> 4) & 0x3;
+            if adaptation_field_control & 0x1 == 0 {
+                return None;
+            }
+            let start = if adaptation_field_control & 0x2 != 0 {
+                5 + p[4] as usize
+            } else {
+                4
+            };
+            Some(Packet {
+                pid: ((p[1] as u16 & 0x1f) << 8) | p[2] as u16,
+                start: p[1] & 0x40 != 0,
+                payload: p.get(start..)?,
+            })
+        })
+        .collect()
+}
+
+/// Get the PSI section in a payload, without its CRC
+fn section(payload: &[u8], table_id: u8) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let section = payload.get(1 + pointer..)?;
+    if *section.first()? != table_id {
+        return None;
+    }
+    let length = ((*section.get(1)? as usize & 0x0f) << 8) | *section.get(2)? as usize;
+    section.get(..(3 + length).checked_sub(4)?)
+}
+
+/// Get the PID of the PMT of the first program in a PAT
+fn parse_pat(payload: &[u8]) -> Option<u16> {
+    let section = section(payload, 0x00)?;
+    section.get(8..)?.chunks_exact(4).find_map(|entry| {
+        let program = ((entry[0] as u16) << 8) | entry[1] as u16;
+        // Program 0 points to the network information table
+        if program == 0 {
+            None
+        } else {
+            Some(((entry[2] as u16 & 0x1f) << 8) | entry[3] as u16)
+        }
+    })
+}
+
+/// Get the PIDs and types of the elementary streams in a PMT
+fn parse_pmt(payload: &[u8]) -> Option<Vec<(u16, StreamType)>> {
+    let section = section(payload, 0x02)?;
+    let program_info_length =
+        ((*section.get(10)? as usize & 0x0f) << 8) | *section.get(11)? as usize;
+    let mut entries = section.get(12 + program_info_length..)?;
+
+    let mut streams = Vec::new();
+    while entries.len() >= 5 {
+        let pid = ((entries[1] as u16 & 0x1f) << 8) | entries[2] as u16;
+        let es_info_length = ((entries[3] as usize & 0x0f) << 8) | entries[4] as usize;
+        let descriptors = entries.get(5..5 + es_info_length).unwrap_or_default();
+        let stream_type = match entries[0] {
+            0x02 => Some(StreamType::Mpeg2Video),
+            0x1b => Some(StreamType::H264),
+            0x03 | 0x04 => Some(StreamType::MpegAudio),
+            0x0f => Some(StreamType::Aac),
+            0x81 => Some(StreamType::Ac3),
+            0x87 => Some(StreamType::Eac3),
+            // DVB carries (E-)AC-3 as private data, with a descriptor that tells what it is
+            0x06 => private_stream_type(descriptors),
+            _ => None,
+        };
+        if let Some(stream_type) = stream_type {
+            streams.push((pid, stream_type));
+        }
+        entries = entries.get(5 + es_info_length..).unwrap_or_default();
+    }
+    Some(streams)
+}
+
+/// Figure out the type of a private data stream from its descriptors
+fn private_stream_type(mut descriptors: &[u8]) -> Option<StreamType> {
+    while descriptors.len() >= 2 {
+        let length = descriptors[1] as usize;
+        match (descriptors[0], descriptors.get(2..2 + length)) {
+            (0x6a, _) => return Some(StreamType::Ac3),
+            (0x7a, _) => return Some(StreamType::Eac3),
+            (0x05, Some(b"AC-3")) => return Some(StreamType::Ac3),
+            (0x05, Some(b"EAC3")) => return Some(StreamType::Eac3),
+            _ => {}
+        }
+        descriptors = descriptors.get(2 + length..).unwrap_or_default();
+    }
+    None
+}
+
+/// Collect the elementary stream data of `pid`, starting at the first PES packet. PES headers are
+/// left out.
+fn es_data(packets: &[Packet], pid: u16) -> Vec<u8> {
+    let mut data = Vec::new();
+    for packet in packets
+        .iter()
+        .filter(|p| p.pid == pid)
+        .skip_while(|p| !p.start)
+    {
+        let payload = if packet.start {
+            if !packet.payload.starts_with(&[0, 0, 1]) {
+                continue;
+            }
+            match packet
+                .payload
+                .get(8)
+                .and_then(|l| packet.payload.get(9 + *l as usize..))
+            {
+                Some(p) => p,
+                None => continue,
+            }
+        } else {
+            packet.payload
+        };
+        data.extend_from_slice(payload);
+        if data.len() >= MAX_ES_DATA {
+            break;
+        }
+    }
+    data
+}
+
+/// Find the sequence header of an MPEG-2 video stream
+fn parse_mpeg2_video(data: &[u8]) -> Option<VideoInfo> {
+    let start = data.windows(4).position(|w| w == [0, 0, 1, 0xb3])?;
+    let header = data.get(start + 4..start + 8)?;
+    let width = ((header[0] as u32) << 4) | (header[1] as u32 >> 4);
+    let height = ((header[1] as u32 & 0x0f) << 8) | header[2] as u32;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let aspect = match header[3] >> 4 {
+        2 => 4.0 / 3.0,
+        3 => 16.0 / 9.0,
+        4 => 2.21,
+        _ => width as f32 / height as f32,
+    };
+    Some(VideoInfo {
+        codec: VideoCodec::Mpeg2,
+        width,
+        height,
+        aspect,
+        frame_rate: MPEG2_FRAME_RATES
+            .get((header[3] & 0x0f) as usize)
+            .copied()
+            .filter(|r| *r > 0.0),
+    })
+}
+
+/// Find and parse the sequence parameter set of an H.264 stream
+fn parse_h264(data: &[u8]) -> Option<VideoInfo> {
+    let start = data
+        .windows(4)
+        .position(|w| w[..3] == [0, 0, 1] && w[3] & 0x1f == 7)?;
+    let nal = &data[start + 4..];
+    let end = nal
+        .windows(3)
+        .position(|w| w == [0, 0, 1])
+        .unwrap_or(nal.len());
+
+    // Remove the emulation prevention bytes (0x000003)
+    let mut sps = Vec::with_capacity(end);
+    let mut zeros = 0;
+    for &byte in &nal[..end] {
+        if byte == 3 && zeros >= 2 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        sps.push(byte);
+    }
+
+    let mut r = BitReader::new(&sps);
+    let profile = r.bits(8)? as u8;
+    let constraints = r.bits(8)? as u8;
+    let level = r.bits(8)? as u8;
+    r.ue()?; // seq_parameter_set_id
+
+    let mut chroma_format = 1;
+    if HIGH_PROFILES.contains(&profile) {
+        chroma_format = r.ue()?;
+        if chroma_format == 3 {
+            r.bits(1)?; // separate_colour_plane_flag
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bits(1)?; // qpprime_y_zero_transform_bypass_flag
+        if r.flag()? {
+            let lists = if chroma_format == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.flag()? {
+                    r.skip_scaling_list(if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bits(1)?; // delta_pic_order_always_zero_flag
+            r.se()?; // offset_for_non_ref_pic
+            r.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..r.ue()? {
+                r.se()?; // offset_for_ref_frame
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bits(1)?; // gaps_in_frame_num_value_allowed_flag
+
+    // Sizes come straight from the stream, so a corrupt SPS mustn't overflow
+    let width_in_mbs = r.ue()?.checked_add(1)?;
+    let height_in_map_units = r.ue()?.checked_add(1)?;
+    let frame_mbs_only = r.flag()?;
+    if !frame_mbs_only {
+        r.bits(1)?; // mb_adaptive_frame_field_flag
+    }
+    r.bits(1)?; // direct_8x8_inference_flag
+
+    let field_factor = if frame_mbs_only { 1 } else { 2 };
+    let mut width = width_in_mbs.checked_mul(16)?;
+    let mut height = height_in_map_units.checked_mul(16 * field_factor)?;
+    if r.flag()? {
+        let (crop_x, crop_y) = match chroma_format {
+            1 => (2, 2 * field_factor),
+            2 => (2, field_factor),
+            _ => (1, field_factor),
+        };
+        let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+        width = width.checked_sub(left.checked_add(right)?.checked_mul(crop_x)?)?;
+        height = height.checked_sub(top.checked_add(bottom)?.checked_mul(crop_y)?)?;
+    }
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    // The sample aspect ratio and frame rate are in the VUI, which is optional
+    let mut sample_aspect = (1, 1);
+    let mut frame_rate = None;
+    if r.flag() == Some(true) {
+        let _ = (|| {
+            if r.flag()? {
+                sample_aspect = match r.bits(8)? {
+                    255 => (r.bits(16)?, r.bits(16)?),
+                    idc => SAMPLE_ASPECT_RATIOS
+                        .get(idc as usize)
+                        .copied()
+                        .unwrap_or((1, 1)),
+                };
+            }
+            if r.flag()? {
+                r.bits(1)?; // overscan_appropriate_flag
+            }
+            if r.flag()? {
+                r.bits(4)?; // video_format, video_full_range_flag
+                if r.flag()? {
+                    r.bits(24)?; // colour_primaries, transfer_characteristics, matrix_coefficients
+                }
+            }
+            if r.flag()? {
+                r.ue()?; // chroma_sample_loc_type_top_field
+                r.ue()?; // chroma_sample_loc_type_bottom_field
+            }
+            if r.flag()? {
+                let units_in_tick = r.bits(32)?;
+                let time_scale = r.bits(32)?;
+                if units_in_tick > 0 {
+                    let rate = time_scale as f32 / (2 * units_in_tick as u64) as f32;
+                    frame_rate = Some((rate * 1000.0).round() / 1000.0);
+                }
+            }
+            Some(())
+        })();
+    }
+    if sample_aspect.0 == 0 || sample_aspect.1 == 0 {
+        sample_aspect = (1, 1);
+    }
+
+    Some(VideoInfo {
+        codec: VideoCodec::H264 {
+            profile,
+            constraints,
+            level,
+        },
+        width,
+        height,
+        aspect: (width as f32 * sample_aspect.0 as f32) / (height as f32 * sample_aspect.1 as f32),
+        frame_rate,
+    })
+}
+
+/// Find and parse the first (E-)AC-3 sync frame
+fn parse_ac3(data: &[u8]) -> Option<AudioInfo> {
+    let start = data.windows(2).position(|w| w == [0x0b, 0x77])?;
+    let frame = data.get(start..start + 8)?;
+    let bsid = frame[5] >> 3;
+
+    if bsid > 10 {
+        // E-AC-3
+        let mut r = BitReader::new(&frame[2..]);
+        r.bits(2)?; // strmtyp
+        r.bits(3)?; // substreamid
+        r.bits(11)?; // frmsiz
+        let sample_rate = match r.bits(2)? {
+            3 => [24000, 22050, 16000].get(r.bits(2)? as usize).copied(),
+            fscod => {
+                r.bits(2)?; // numblkscod
+                [48000, 44100, 32000].get(fscod as usize).copied()
+            }
+        };
+        let acmod = r.bits(3)? as usize;
+        let lfe = r.flag()?;
+        return Some(AudioInfo {
+            codec: AudioCodec::Eac3,
+            channels: AC3_CHANNELS[acmod],
+            lfe,
+            sample_rate,
+        });
+    }
+
+    let sample_rate = [48000, 44100, 32000].get((frame[4] >> 6) as usize).copied();
+    let mut r = BitReader::new(&frame[6..]);
+    let acmod = r.bits(3)? as usize;
+    if acmod & 0x1 != 0 && acmod != 1 {
+        r.bits(2)?; // cmixlev
+    }
+    if acmod & 0x4 != 0 {
+        r.bits(2)?; // surmixlev
+    }
+    if acmod == 2 {
+        r.bits(2)?; // dsurmod
+    }
+    let lfe = r.flag()?;
+    Some(AudioInfo {
+        codec: AudioCodec::Ac3,
+        channels: AC3_CHANNELS[acmod],
+        lfe,
+        sample_rate,
+    })
+}
+
+/// Find and parse the first ADTS header of an AAC stream
+fn parse_adts(data: &[u8]) -> Option<AudioInfo> {
+    let start = data
+        .windows(2)
+        .position(|w| w[0] == 0xff && w[1] & 0xf6 == 0xf0)?;
+    let header = data.get(start..start + 4)?;
+    let object_type = (header[2] >> 6) + 1;
+    let channel_config = ((header[2] & 0x1) << 2) | (header[3] >> 6);
+    let (channels, lfe) = match channel_config {
+        7 => (7, true),
+        6 => (5, true),
+        c => (c, false),
+    };
+    Some(AudioInfo {
+        codec: AudioCodec::Aac { object_type },
+        channels,
+        lfe,
+        sample_rate: AAC_SAMPLE_RATES
+            .get(((header[2] >> 2) & 0x0f) as usize)
+            .copied(),
+    })
+}
+
+/// Find and parse the first frame header of an MPEG audio stream
+fn parse_mpeg_audio(data: &[u8]) -> Option<AudioInfo> {
+    let start = data
+        .windows(2)
+        .position(|w| w[0] == 0xff && w[1] & 0xe0 == 0xe0 && w[1] & 0x06 != 0)?;
+    let header = data.get(start..start + 4)?;
+    let sample_rates: &[u32] = match (header[1] >> 3) & 0x3 {
+        3 => &[44100, 48000, 32000],
+        2 => &[22050, 24000, 16000],
+        _ => &[11025, 12000, 8000],
+    };
+    Some(AudioInfo {
+        codec: AudioCodec::Mpeg,
+        channels: if header[3] >> 6 == 3 { 1 } else { 2 },
+        lfe: false,
+        sample_rate: sample_rates.get(((header[2] >> 2) & 0x3) as usize).copied(),
+    })
+}
+
+/// Reads bits and Exp-Golomb codes from a byte slice, most significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0 }
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        Some(self.bits(1)? == 1)
+    }
+
+    fn bits(&mut self, count: usize) -> Option<u32> {
+        let mut value: u64 = 0;
+        for _ in 0..count {
+            let byte = self.data.get(self.position / 8)?;
+            let bit = (byte >> (7 - self.position % 8)) & 0x1;
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+        Some(value as u32)
+    }
+
+    /// Unsigned Exp-Golomb code
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while !self.flag()? {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some(((1u64 << zeros) - 1 + self.bits(zeros)? as u64) as u32)
+    }
+
+    /// Signed Exp-Golomb code
+    fn se(&mut self) -> Option<i32> {
+        let value = self.ue()? as i64;
+        Some(if value % 2 == 1 {
+            ((value + 1) / 2) as i32
+        } else {
+            -(value / 2) as i32
+        })
+    }
+
+    fn skip_scaling_list(&mut self, size: usize) -> Option<()> {
+        let mut last: i32 = 8;
+        let mut next = 8;
+        for _ in 0..size {
+            if next != 0 {
+                next = last.checked_add(self.se()?)?.rem_euclid(256);
+            }
+            if next != 0 {
+                last = next;
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes bits and Exp-Golomb codes, the opposite of `BitReader`
+    #[derive(Default)]
+    struct BitWriter {
+        bits: Vec<bool>,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, count: usize, value: u64) -> &mut Self {
+            for i in (0..count).rev() {
+                self.bits.push((value >> i) & 1 == 1);
+            }
+            self
+        }
+
+        fn ue(&mut self, value: u64) -> &mut Self {
+            let code = value + 1;
+            let length = 64 - code.leading_zeros() as usize;
+            self.bits(length - 1, 0).bits(length, code)
+        }
+
+        fn bytes(&self) -> Vec<u8> {
+            let mut bytes = vec![0; self.bits.len().div_ceil(8)];
+            for (i, bit) in self.bits.iter().enumerate() {
+                if *bit {
+                    bytes[i / 8] |= 0x80 >> (i % 8);
+                }
+            }
+            // rbsp_stop_one_bit and padding
+            bytes.push(0x80);
+            bytes
+        }
+    }
+
+    /// A Main profile SPS of `width_in_mbs` x `height_in_map_units` macroblocks with an optional
+    /// bottom crop
+    fn sps(width_in_mbs_minus1: u64, height_in_map_units_minus1: u64, crop_bottom: u64) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.bits(8, 77).bits(8, 0x40).bits(8, 31); // profile, constraints, level
+        w.ue(0); // seq_parameter_set_id
+        w.ue(0); // log2_max_frame_num_minus4
+        w.ue(2); // pic_order_cnt_type
+        w.ue(1); // max_num_ref_frames
+        w.bits(1, 0); // gaps_in_frame_num_value_allowed_flag
+        w.ue(width_in_mbs_minus1);
+        w.ue(height_in_map_units_minus1);
+        w.bits(1, 1); // frame_mbs_only_flag
+        w.bits(1, 1); // direct_8x8_inference_flag
+        if crop_bottom > 0 {
+            w.bits(1, 1).ue(0).ue(0).ue(0).ue(crop_bottom);
+        } else {
+            w.bits(1, 0);
+        }
+        w.bits(1, 0); // vui_parameters_present_flag
+        let mut nal = vec![0, 0, 0, 1, 0x67];
+        nal.extend(w.bytes());
+        nal
+    }
+
+    /// An AC-3 sync frame header with 48 kHz 5.1 audio
+    static AC3_FRAME: [u8; 8] = [0x0b, 0x77, 0x00, 0x00, 0x00, 0x40, 0xe1, 0x00];
+
+    /// An ADTS header with 48 kHz stereo AAC-LC audio
+    static ADTS_HEADER: [u8; 7] = [0xff, 0xf1, 0x4c, 0x80, 0x00, 0x1f, 0xfc];
+
+    /// A TS packet of `pid`, padded with stuffing bytes
+    fn ts_packet(pid: u16, start: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![
+            0x47,
+            ((pid >> 8) as u8 & 0x1f) | if start { 0x40 } else { 0 },
+            pid as u8,
+            0x10,
+        ];
+        packet.extend_from_slice(payload);
+        packet.resize(PACKET_SIZE, 0xff);
+        packet
+    }
+
+    /// A PSI section with a dummy CRC, behind a pointer field
+    fn psi(table_id: u8, body: &[u8]) -> Vec<u8> {
+        let length = body.len() + 5 + 4;
+        let mut payload = vec![0, table_id, 0xb0 | (length >> 8) as u8, length as u8];
+        payload.extend_from_slice(&[0x00, 0x01, 0xc1, 0x00, 0x00]);
+        payload.extend_from_slice(body);
+        payload.extend_from_slice(&[0; 4]);
+        payload
+    }
+
+    fn pat() -> Vec<u8> {
+        // Network information table, then program 1 with its PMT on PID 0x100
+        psi(0x00, &[0x00, 0x00, 0xe0, 0x10, 0x00, 0x01, 0xe1, 0x00])
+    }
+
+    fn pmt() -> Vec<u8> {
+        psi(
+            0x02,
+            &[
+                0xe1, 0x01, 0xf0, 0x00, // PCR PID, no program info
+                0x1b, 0xe1, 0x01, 0xf0, 0x00, // H.264 on 0x101
+                0x81, 0xe1, 0x02, 0xf0, 0x00, // AC-3 on 0x102
+                0x24, 0xe1, 0x03, 0xf0, 0x00, // HEVC, which isn't parsed
+            ],
+        )
+    }
+
+    fn pes(stream_id: u8, data: &[u8]) -> Vec<u8> {
+        let mut pes = vec![0, 0, 1, stream_id, 0, 0, 0x80, 0, 0];
+        pes.extend_from_slice(data);
+        pes
+    }
+
+    #[test]
+    fn pat_and_pmt() {
+        assert_eq!(parse_pat(&pat()), Some(0x100));
+        assert_eq!(
+            parse_pmt(&pmt()),
+            Some(vec![(0x101, StreamType::H264), (0x102, StreamType::Ac3)])
+        );
+        // A PMT is not a PAT
+        assert_eq!(parse_pat(&pmt()), None);
+        // Section length beyond the payload
+        let mut truncated = pat();
+        truncated[3] = 0xff;
+        assert_eq!(parse_pat(&truncated), None);
+    }
+
+    #[test]
+    fn private_ac3_stream() {
+        let pmt = psi(
+            0x02,
+            &[
+                0xe1, 0x01, 0xf0, 0x00, 0x06, 0xe1, 0x02, 0xf0, 0x03, 0x6a, 0x01, 0x00,
+            ],
+        );
+        assert_eq!(parse_pmt(&pmt), Some(vec![(0x102, StreamType::Ac3)]));
+    }
+
+    #[test]
+    fn h264_sps() {
+        let video = parse_h264(&sps(79, 44, 0)).unwrap();
+        assert_eq!((video.width, video.height), (1280, 720));
+        assert_eq!(
+            video.codec,
+            VideoCodec::H264 {
+                profile: 77,
+                constraints: 0x40,
+                level: 31
+            }
+        );
+        assert_eq!(video.aspect_ratio(), "16:9");
+        assert_eq!(video.frame_rate, None);
+
+        // 1920x1088 cropped to 1920x1080
+        let video = parse_h264(&sps(119, 67, 4)).unwrap();
+        assert_eq!((video.width, video.height), (1920, 1080));
+    }
+
+    #[test]
+    fn h264_sps_emulation_prevention() {
+        // Constraints and level are 0 and the SPS id starts with 6 zero bits, so the encoder had
+        // to insert an emulation prevention byte, which must be skipped
+        let mut nal = vec![0, 0, 1, 0x67, 66, 0, 0, 3];
+        let mut w = BitWriter::default();
+        w.ue(63)
+            .ue(0)
+            .ue(2)
+            .ue(1)
+            .bits(1, 0)
+            .ue(39)
+            .ue(29)
+            .bits(3, 0b110)
+            .bits(1, 0);
+        nal.extend(w.bytes());
+        let video = parse_h264(&nal).unwrap();
+        assert_eq!((video.width, video.height), (640, 480));
+    }
+
+    #[test]
+    fn h264_sps_truncated() {
+        let sps = sps(79, 44, 0);
+        for length in 5..sps.len() - 2 {
+            assert_eq!(parse_h264(&sps[..length]), None, "length {}", length);
+        }
+    }
+
+    #[test]
+    fn h264_sps_oversized() {
+        // Macroblock counts that don't fit in a u32 once multiplied
+        assert_eq!(parse_h264(&sps(0xffff_fffe, 44, 0)), None);
+        assert_eq!(parse_h264(&sps(79, 0xffff_fffe, 0)), None);
+        // Cropping more than the picture
+        assert_eq!(parse_h264(&sps(79, 44, 0xffff_fffe)), None);
+        assert_eq!(parse_h264(&sps(79, 44, 1000)), None);
+    }
+
+    #[test]
+    fn scaling_list_overflow() {
+        // A delta that would overflow an i32
+        let mut w = BitWriter::default();
+        w.ue(0xffff_fffe);
+        let bytes = w.bytes();
+        let mut r = BitReader::new(&bytes);
+        assert_eq!(r.skip_scaling_list(16), None);
+    }
+
+    #[test]
+    fn adts() {
+        let audio = parse_adts(&ADTS_HEADER).unwrap();
+        assert_eq!(audio.codec, AudioCodec::Aac { object_type: 2 });
+        assert_eq!((audio.channels, audio.lfe), (2, false));
+        assert_eq!(audio.sample_rate, Some(48000));
+        assert_eq!(audio.stereo(), "stereo");
+        assert_eq!(parse_adts(&ADTS_HEADER[..3]), None);
+    }
+
+    #[test]
+    fn ac3() {
+        let mut data = vec![0x12, 0x34];
+        data.extend_from_slice(&AC3_FRAME);
+        let audio = parse_ac3(&data).unwrap();
+        assert_eq!(audio.codec, AudioCodec::Ac3);
+        assert_eq!((audio.channels, audio.lfe), (5, true));
+        assert_eq!(audio.sample_rate, Some(48000));
+        assert_eq!(audio.layout(), "5.1");
+        assert_eq!(parse_ac3(&AC3_FRAME[..6]), None);
+    }
+
+    #[test]
+    fn transport_stream() {
+        let mut ts = vec![0x00, 0x47];
+        ts.extend(ts_packet(0x0000, true, &pat()));
+        ts.extend(ts_packet(0x0100, true, &pmt()));
+        ts.extend(ts_packet(0x0101, true, &pes(0xe0, &sps(79, 44, 0))));
+        ts.extend(ts_packet(0x0102, true, &pes(0xbd, &AC3_FRAME)));
+
+        let info = probe(&ts).unwrap();
+        let video = info.video.unwrap();
+        assert_eq!((video.width, video.height), (1280, 720));
+        assert_eq!(info.audio.unwrap().codec, AudioCodec::Ac3);
+        assert_eq!(probe(&[0x47; 100]), None);
+    }
+}
@@ -0,0 +1,173 @@
+use super::StreamStatus;
+use crate::errors::AppError;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{
+    stream::{self, AbortHandle},
+    Stream, StreamExt,
+};
+use reqwest::Url;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use uuid::Uuid;
+
+/// Keeps track of all streams that are being served to clients, so they can be listed and
+/// terminated.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+struct Session {
+    station_id: String,
+    call_sign: String,
+    client_address: String,
+    user_agent: String,
+    start_time: DateTime<Utc>,
+    bytes_sent: Arc<AtomicU64>,
+    status: Arc<Mutex<StreamStatus>>,
+    abort: AbortHandle,
+}
+
+/// A client session as reported by `/streams`
+#[derive(Serialize)]
+pub struct SessionInfo {
+    id: String,
+    stream_id: String,
+    station_id: String,
+    call_sign: String,
+    client_address: String,
+    user_agent: String,
+    start_time: String,
+    seconds_served: f32,
+    bytes_sent: u64,
+    upstream_host: Option<String>,
+    url_refreshed: bool,
+    gaps: usize,
+    discontinuities: usize,
+    recoveries: usize,
+}
+
+/// Removes a session from `Sessions` when the stream of the session is dropped
+struct SessionGuard {
+    id: String,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions::default()
+    }
+
+    /// Register a new session for `stream`. The returned stream counts the bytes that are sent
+    /// and ends when the session is killed.
+    pub fn track<S: Stream<Item = Bytes>>(
+        &self,
+        stream: S,
+        station_id: &str,
+        call_sign: &str,
+        client_address: &str,
+        user_agent: &str,
+        status: Arc<Mutex<StreamStatus>>,
+    ) -> impl Stream<Item = Bytes> {
+        let id = Uuid::new_v4().to_string()[0..7].to_string();
+        let (stream, abort) = stream::abortable(stream);
+        let bytes_sent = Arc::new(AtomicU64::new(0));
+
+        info!(
+            "Session {} - {} ({}) started watching station {} (stream {})",
+            id,
+            client_address,
+            user_agent,
+            station_id,
+            status.lock().unwrap().stream_id
+        );
+
+        self.sessions.lock().unwrap().insert(
+            id.to_owned(),
+            Session {
+                station_id: station_id.to_owned(),
+                call_sign: call_sign.to_owned(),
+                client_address: client_address.to_owned(),
+                user_agent: user_agent.to_owned(),
+                start_time: Utc::now(),
+                bytes_sent: bytes_sent.clone(),
+                status,
+                abort,
+            },
+        );
+
+        let guard = SessionGuard {
+            id,
+            sessions: self.sessions.clone(),
+        };
+        stream.map(move |chunk| {
+            let _guard = &guard;
+            bytes_sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            chunk
+        })
+    }
+
+    /// List all active sessions, oldest first
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut sorted: Vec<(&String, &Session)> = sessions.iter().collect();
+        sorted.sort_by_key(|(_, s)| s.start_time);
+        sorted
+            .into_iter()
+            .map(|(id, s)| {
+                let status = s.status.lock().unwrap();
+                SessionInfo {
+                    id: id.to_owned(),
+                    stream_id: status.stream_id.to_owned(),
+                    station_id: s.station_id.to_owned(),
+                    call_sign: s.call_sign.to_owned(),
+                    client_address: s.client_address.to_owned(),
+                    user_agent: s.user_agent.to_owned(),
+                    start_time: s.start_time.to_rfc3339(),
+                    seconds_served: status.seconds_served,
+                    bytes_sent: s.bytes_sent.load(Ordering::Relaxed),
+                    upstream_host: Url::parse(&status.url)
+                        .ok()
+                        .and_then(|u| u.host_str().map(|h| h.to_owned())),
+                    url_refreshed: status.refreshed,
+                    gaps: status.gaps,
+                    discontinuities: status.discontinuities,
+                    recoveries: status.recoveries,
+                }
+            })
+            .collect()
+    }
+
+    /// Terminate session `id`. The stream to the client ends after the current chunk.
+    pub fn kill(&self, id: &str) -> Result<(), AppError> {
+        match self.sessions.lock().unwrap().get(id) {
+            Some(session) => {
+                info!("Session {} - terminating", id);
+                session.abort.abort();
+                Ok(())
+            }
+            None => Err(AppError::NotFound),
+        }
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if let Some(session) = self.sessions.lock().unwrap().remove(&self.id) {
+            info!(
+                "Session {} - {} stopped watching station {} ({} bytes sent)",
+                self.id,
+                session.client_address,
+                session.station_id,
+                session.bytes_sent.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use super::{expiry::refresh_time, fetch_text, LIVE_EDGE_SEGMENTS};
+use crate::{
+    config::VariantPolicy,
+    errors::AppError,
+    service::{
+        station_provider::StationProvider,
+        variant::{select_variant, subtitle_rendition},
+    },
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
+use reqwest::Url;
+use std::{collections::VecDeque, str::FromStr};
+use tokio::time::{sleep, Duration};
+
+// How often (in seconds) the subtitle playlist is fetched when there are no new segments
+static POLL_INTERVAL: u64 = 2;
+
+// How many times in a row the subtitle playlist may fail to load before we give up
+static MAX_FAILURES: u8 = 5;
+
+struct SubtitleState<T: StationProvider> {
+    service: T,
+    id: String,
+    policy: VariantPolicy,
+    language: Option<String>,
+    url: String,
+    refresh_at: DateTime<Utc>,
+    next_sequence: Option<usize>,
+    segments: VecDeque<String>,
+    header_sent: bool,
+    base: Option<f64>,
+    failures: u8,
+}
+
+/// Follow the live subtitle playlist of station `id` and return its cues as a single WebVTT
+/// document that doesn't end. Cue times are relative to the start of the document. The subtitles
+/// in `language` are used, or the first subtitles of the station if no language is given.
+pub async fn subtitles<T: 'static + StationProvider>(
+    service: T,
+    id: &str,
+    policy: VariantPolicy,
+    language: Option<&str>,
+) -> Result<impl Stream<Item = Bytes>, AppError> {
+    let url = subtitle_url(&service, id, &policy, language, false).await?;
+    info!("Following subtitles of station {}: {}", id, url);
+
+    let state = SubtitleState {
+        service,
+        id: id.to_owned(),
+        policy,
+        language: language.map(|l| l.to_owned()),
+        refresh_at: refresh_time(&url),
+        url,
+        next_sequence: None,
+        segments: VecDeque::new(),
+        header_sent: false,
+        base: None,
+        failures: 0,
+    };
+
+    Ok(stream::unfold(state, |mut state| async move {
+        if !state.header_sent {
+            state.header_sent = true;
+            return Some((Bytes::from_static(b"WEBVTT\n\n"), state));
+        }
+
+        loop {
+            // Serve the cues of segments we haven't served yet
+            if let Some(url) = state.segments.pop_front() {
+                match fetch_text(&url).await {
+                    Some(data) => {
+                        let cues = rewrite_cues(&data, &mut state.base);
+                        if !cues.is_empty() {
+                            return Some((Bytes::from(cues), state));
+                        }
+                    }
+                    None => warn!("Unable to get subtitle segment {}", url),
+                }
+                continue;
+            }
+
+            if Utc::now() >= state.refresh_at {
+                match subtitle_url(
+                    &state.service,
+                    &state.id,
+                    &state.policy,
+                    state.language.as_deref(),
+                    true,
+                )
+                .await
+                {
+                    Ok(url) => {
+                        state.refresh_at = refresh_time(&url);
+                        state.url = url;
+                    }
+                    Err(e) => {
+                        warn!("Unable to refresh subtitles of station {}: {}", state.id, e);
+                        return None;
+                    }
+                }
+            }
+
+            let playlist = match fetch_text(&state.url)
+                .await
+                .and_then(|d| hls_m3u8::MediaPlaylist::from_str(d.as_str()).ok())
+            {
+                Some(p) => p,
+                None => {
+                    state.failures += 1;
+                    if state.failures > MAX_FAILURES {
+                        warn!(
+                            "Unable to get subtitles of station {}. Stopping subtitles..",
+                            state.id
+                        );
+                        return None;
+                    }
+                    // The URL might have expired
+                    state.refresh_at = Utc::now();
+                    sleep(Duration::from_secs(POLL_INTERVAL)).await;
+                    continue;
+                }
+            };
+            state.failures = 0;
+
+            let first_sequence = playlist.media_sequence;
+            let count = playlist.segments.iter().count();
+            if count > 0 {
+                let last_sequence = first_sequence + count - 1;
+                // Start close to the live edge, like video streams do
+                let next = state.next_sequence.unwrap_or_else(|| {
+                    (last_sequence + 1)
+                        .saturating_sub(LIVE_EDGE_SEGMENTS)
+                        .max(first_sequence)
+                });
+                let playlist_url = Url::parse(&state.url).unwrap();
+                for (_i, ms) in playlist.segments {
+                    if ms.number() >= next {
+                        let url = playlist_url.join(ms.uri()).unwrap().to_string();
+                        state.segments.push_back(url);
+                    }
+                }
+                state.next_sequence = Some(next.max(last_sequence + 1));
+            }
+
+            if state.segments.is_empty() {
+                sleep(Duration::from_secs(POLL_INTERVAL)).await;
+            }
+        }
+    }))
+}
+
+/// Resolve the URL of the subtitle playlist of station `id`
+async fn subtitle_url<T: StationProvider>(
+    service: &T,
+    id: &str,
+    policy: &VariantPolicy,
+    language: Option<&str>,
+    fresh: bool,
+) -> Result<String, AppError> {
+    let variants = if fresh {
+        service.refresh_station_variants(id).await?
+    } else {
+        service.station_variants(id).await?
+    };
+    let variant = select_variant(&variants, policy).ok_or(AppError::NotFound)?;
+    subtitle_rendition(variant, language)
+        .and_then(|r| r.url.to_owned())
+        .ok_or_else(|| {
+            warn!(
+                "Station {} doesn't have {} subtitles",
+                id,
+                language.unwrap_or("any")
+            );
+            AppError::NotFound
+        })
+}
+
+/// Take the cues from a WebVTT segment and make their times relative to `base`, the start of the
+/// first segment that was served. The start of a segment comes from its `X-TIMESTAMP-MAP`, which
+/// maps cue times to the MPEG-TS clock of the video.
+fn rewrite_cues(data: &str, base: &mut Option<f64>) -> String {
+    let data = data.replace("\r\n", "\n");
+    let blocks: Vec<&str> = data
+        .split("\n\n")
+        .map(|b| b.trim_matches('\n'))
+        .filter(|b| !b.is_empty())
+        .collect();
+
+    let (offset, start) = match blocks.first().and_then(|h| timestamp_map(h)) {
+        Some((mpegts, local)) => (mpegts - local, mpegts),
+        None => (0.0, 0.0),
+    };
+    let shift = offset - *base.get_or_insert(start);
+
+    let mut cues = String::new();
+    for block in blocks {
+        // Only cues are kept, the header, comments and styles are dropped
+        if !block.lines().any(|l| l.contains("-->")) || block.starts_with("WEBVTT") {
+            continue;
+        }
+        for line in block.lines() {
+            if line.contains("-->") {
+                cues.push_str(&shift_timing(line, shift));
+            } else {
+                cues.push_str(line);
+            }
+            cues.push('\n');
+        }
+        cues.push('\n');
+    }
+    cues
+}
+
+/// Parse `X-TIMESTAMP-MAP=MPEGTS:<ticks>,LOCAL:<time>` into seconds on the MPEG-TS clock and
+/// seconds in cue time
+fn timestamp_map(header: &str) -> Option<(f64, f64)> {
+    let map = header
+        .lines()
+        .find_map(|l| l.strip_prefix("X-TIMESTAMP-MAP="))?;
+    let mut mpegts = None;
+    let mut local = None;
+    for field in map.split(',') {
+        if let Some(ticks) = field.strip_prefix("MPEGTS:") {
+            mpegts = ticks.parse::<f64>().ok().map(|t| t / 90000.0);
+        } else if let Some(time) = field.strip_prefix("LOCAL:") {
+            local = parse_time(time);
+        }
+    }
+    Some((mpegts?, local?))
+}
+
+/// Shift the start and end time of a cue timing line (`start --> end [settings]`)
+fn shift_timing(line: &str, shift: f64) -> String {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    match (
+        parts.first().and_then(|t| parse_time(t)),
+        parts.get(2).and_then(|t| parse_time(t)),
+    ) {
+        (Some(start), Some(end)) => {
+            let mut timing = format!(
+                "{} --> {}",
+                format_time(start + shift),
+                format_time(end + shift)
+            );
+            for setting in parts.iter().skip(3) {
+                timing.push(' ');
+                timing.push_str(setting);
+            }
+            timing
+        }
+        _ => line.to_owned(),
+    }
+}
+
+/// Parse a WebVTT time (`hh:mm:ss.ttt` or `mm:ss.ttt`) into seconds
+fn parse_time(time: &str) -> Option<f64> {
+    time.split(':').try_fold(0.0, |total, part| {
+        Some(total * 60.0 + part.parse::<f64>().ok()?)
+    })
+}
+
+/// Format seconds as a WebVTT time. Negative times are clamped to 0.
+fn format_time(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
@@ -0,0 +1,295 @@
+use super::{align, hub::Hubs, StreamStatus};
+use crate::{config::Config, errors::AppError, service::station_provider::StationProvider};
+use bytes::Bytes;
+use chrono::Utc;
+use futures::{stream, Stream, StreamExt};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::time::{sleep, Duration};
+
+// Chunks are collected until they add up to this many bytes before they're written to disk, so the
+// buffer doesn't end up with a file for every few kilobytes of video
+static ENTRY_SIZE: usize = 1024 * 1024;
+
+/// Rolling on-disk buffers of the most recent chunks of selected stations, so playback can start
+/// in the past. Buffers are fed by a regular subscription on the station's `Hub` and live in
+/// `{cache_directory}/timeshift/{station_id}`. Each chunk is stored in a file named after the time
+/// (in milliseconds) it was received.
+pub struct TimeShift {
+    config: Arc<Config>,
+    buffers: HashMap<String, Arc<Mutex<Buffer>>>,
+}
+
+struct Buffer {
+    dir: PathBuf,
+    entries: VecDeque<Entry>,
+    size: u64,
+    status: Option<Arc<Mutex<StreamStatus>>>,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    time: i64,
+    size: u64,
+}
+
+impl TimeShift {
+    /// Create buffers for all stations in `timeshift_stations`. Chunks that are left on disk from
+    /// a previous run are picked up again, as long as they're recent enough. Stations whose buffer
+    /// directory can't be used don't get a buffer.
+    pub fn new(config: Arc<Config>) -> TimeShift {
+        let mut buffers = HashMap::new();
+        for id in config.timeshift_stations.iter().flatten() {
+            let dir = config.cache_directory.join("timeshift").join(id);
+            let entries = match read_entries(&dir) {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!(
+                        "Unable to use time-shift buffer {}, disabling time-shift for station {}: {}",
+                        dir.display(),
+                        id,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            let mut buffer = Buffer {
+                dir,
+                size: entries.iter().map(|e| e.size).sum(),
+                entries: entries.into(),
+                status: None,
+            };
+            for path in buffer.prune(&config) {
+                let _ = fs::remove_file(path);
+            }
+            buffers.insert(id.to_owned(), Arc::new(Mutex::new(buffer)));
+        }
+
+        TimeShift { config, buffers }
+    }
+
+    /// Start recording all buffered stations that are served by `service`. Recording goes through
+    /// `hubs`, so viewers of the live stream share the upstream session with the recorder.
+    pub fn record<T: 'static + StationProvider + Send + Sync + Clone>(
+        &self,
+        hubs: Arc<Hubs>,
+        service: T,
+    ) {
+        for (id, buffer) in self.buffers.iter() {
+            let id = id.to_owned();
+            let buffer = buffer.clone();
+            let hubs = hubs.clone();
+            let service = service.clone();
+            let config = self.config.clone();
+
+            tokio::spawn(async move {
+                // Stations are only recorded by the service that serves them
+                let served = service
+                    .stations()
+                    .await
+                    .lock()
+                    .await
+                    .iter()
+                    .any(|s| s.id.to_string() == id);
+                if !served {
+                    return;
+                }
+
+                info!(
+                    "Time-shift buffer for station {} in {} ({} minutes, max {} MB)",
+                    id,
+                    buffer.lock().unwrap().dir.display(),
+                    config.timeshift_minutes,
+                    config.timeshift_max_size
+                );
+
+                loop {
+                    match hubs
+                        .subscribe(
+                            &id,
+                            &service,
+                            None,
+                            config.variant_policy.clone(),
+                            config.stream_start,
+                        )
+                        .await
+                    {
+                        Ok((upstream, status)) => {
+                            buffer.lock().unwrap().status = Some(status);
+                            futures::pin_mut!(upstream);
+                            let mut pending = Vec::with_capacity(ENTRY_SIZE);
+                            let mut received = Utc::now().timestamp_millis();
+                            while let Some(chunk) = upstream.next().await {
+                                if pending.is_empty() {
+                                    received = Utc::now().timestamp_millis();
+                                }
+                                pending.extend_from_slice(&chunk);
+                                if pending.len() >= ENTRY_SIZE {
+                                    append(&buffer, &pending, received, &config).await;
+                                    pending.clear();
+                                }
+                            }
+                            if !pending.is_empty() {
+                                append(&buffer, &pending, received, &config).await;
+                            }
+                            warn!("Time-shift recording for station {} ended", id);
+                        }
+                        Err(e) => warn!("Unable to record station {}: {}", id, e),
+                    }
+                    sleep(Duration::from_secs(10)).await;
+                }
+            });
+        }
+    }
+
+    /// Play station `id` starting `offset` seconds ago. Playback stays `offset` seconds behind
+    /// live. If the buffer doesn't go back that far, playback starts at the oldest chunk.
+    pub fn watch(
+        &self,
+        id: &str,
+        offset: u64,
+    ) -> Result<(impl Stream<Item = Bytes>, Arc<Mutex<StreamStatus>>), AppError> {
+        let buffer = match self.buffers.get(id) {
+            Some(b) => b.clone(),
+            None => {
+                warn!("Station {} doesn't have a time-shift buffer", id);
+                return Err(AppError::BadRequest);
+            }
+        };
+        let status = buffer
+            .lock()
+            .unwrap()
+            .status
+            .clone()
+            .ok_or(AppError::NotFound)?;
+
+        let offset = offset as i64 * 1000;
+        let next = Utc::now().timestamp_millis() - offset;
+        let max_wait = self.config.stall_timeout as i64 * 1000;
+        // Besides the next chunk, keep track of whether playback has been aligned to a packet yet
+        let state = (buffer, next, false);
+        let stream = stream::unfold(state, move |(buffer, mut next, mut aligned)| async move {
+            let mut waiting_since = Utc::now().timestamp_millis();
+            loop {
+                let found = {
+                    let buffer = buffer.lock().unwrap();
+                    buffer
+                        .entries
+                        .iter()
+                        .find(|e| e.time >= next)
+                        .map(|e| (e.time, buffer.path(e)))
+                };
+
+                let (time, path) = match found {
+                    Some(f) => f,
+                    None if Utc::now().timestamp_millis() - waiting_since > max_wait => {
+                        warn!("Time-shift buffer isn't growing anymore. Stopping stream..");
+                        return None;
+                    }
+                    None => {
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                // Serve chunks with the same timing as they were recorded
+                let wait = time + offset - Utc::now().timestamp_millis();
+                if wait > 0 {
+                    sleep(Duration::from_millis(wait as u64)).await;
+                }
+
+                next = time + 1;
+                waiting_since = Utc::now().timestamp_millis();
+                // The chunk might have been pruned in the meantime, in which case we just move
+                // on to the next one.
+                if let Ok(mut data) = tokio::fs::read(path).await {
+                    // Chunks are stored back to back, so only the first one has to be aligned
+                    if !aligned {
+                        align(&mut data);
+                        if data.is_empty() {
+                            continue;
+                        }
+                        aligned = true;
+                    }
+                    return Some((Bytes::from(data), (buffer, next, aligned)));
+                }
+            }
+        });
+        Ok((stream, status))
+    }
+}
+
+impl Buffer {
+    fn path(&self, entry: &Entry) -> PathBuf {
+        self.dir.join(format!("{}.ts", entry.time))
+    }
+
+    /// Drop entries that are too old or don't fit anymore and return the files to remove
+    fn prune(&mut self, config: &Config) -> Vec<PathBuf> {
+        let oldest = Utc::now().timestamp_millis() - config.timeshift_minutes as i64 * 60 * 1000;
+        let max_size = config.timeshift_max_size * 1024 * 1024;
+        let mut removed = Vec::new();
+        while let Some(entry) = self.entries.front().copied() {
+            if entry.time >= oldest && self.size <= max_size {
+                break;
+            }
+            self.entries.pop_front();
+            self.size -= entry.size;
+            removed.push(self.path(&entry));
+        }
+        removed
+    }
+}
+
+/// Create buffer directory `dir` if needed and return the chunks that are already in it, oldest
+/// first
+fn read_entries(dir: &Path) -> std::io::Result<Vec<Entry>> {
+    fs::create_dir_all(dir)?;
+    let mut entries: Vec<Entry> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let time = e.path().file_stem()?.to_str()?.parse::<i64>().ok()?;
+            let size = e.metadata().ok()?.len();
+            Some(Entry { time, size })
+        })
+        .collect();
+    entries.sort_by_key(|e| e.time);
+    Ok(entries)
+}
+
+/// Write a chunk that was received at `time` to the buffer and prune the buffer
+async fn append(buffer: &Arc<Mutex<Buffer>>, chunk: &[u8], time: i64, config: &Config) {
+    let (entry, path) = {
+        let buffer = buffer.lock().unwrap();
+        // Make sure every chunk gets its own file, even if chunks arrive within a millisecond
+        let last = buffer.entries.back().map(|e| e.time + 1).unwrap_or(0);
+        let entry = Entry {
+            time: time.max(last),
+            size: chunk.len() as u64,
+        };
+        (entry, buffer.path(&entry))
+    };
+    if let Err(e) = tokio::fs::write(&path, chunk).await {
+        warn!(
+            "Unable to write to time-shift buffer {}: {}",
+            path.display(),
+            e
+        );
+        return;
+    }
+
+    let removed = {
+        let mut buffer = buffer.lock().unwrap();
+        buffer.entries.push_back(entry);
+        buffer.size += entry.size;
+        buffer.prune(config)
+    };
+    for path in removed {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
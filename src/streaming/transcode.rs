@@ -0,0 +1,125 @@
+use crate::{config::TranscodeProfile, errors::AppError};
+use actix_web::rt;
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use std::process::Stdio;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdout, Command},
+    task::JoinHandle,
+};
+
+// Size of the chunks that are read from ffmpeg
+static CHUNK_SIZE: usize = 64 * 1024;
+
+/// A running ffmpeg process together with the tasks that feed it and log its output. Dropping a
+/// `Transcoder` kills ffmpeg, so ffmpeg is stopped as soon as the client disconnects.
+struct Transcoder {
+    profile: String,
+    child: Child,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// Pipe `input` through ffmpeg and return a stream of the resulting MPEG-TS output. The stream is
+/// transcoded using `profile` (a name and a `TranscodeProfile`), or copied if there's no profile.
+/// If `audio` is set, the audio of `input` is replaced with the audio of that media playlist.
+pub fn transcode<S: 'static + Stream<Item = Bytes>>(
+    input: S,
+    ffmpeg: &str,
+    profile: Option<(&str, &TranscodeProfile)>,
+    audio: Option<&str>,
+) -> Result<impl Stream<Item = Bytes>, AppError> {
+    let mut command = Command::new(ffmpeg);
+    command.args(["-hide_banner", "-loglevel", "warning", "-i", "pipe:0"]);
+    if let Some(audio) = audio {
+        // Keep the original timestamps, so audio and video stay in sync
+        command.args(["-copyts", "-i", audio, "-map", "0:v", "-map", "1:a"]);
+    }
+    let name = match profile {
+        Some((name, profile)) => {
+            command.args(profile.args.split_whitespace());
+            name
+        }
+        None => {
+            command.args(["-c", "copy"]);
+            "copy"
+        }
+    };
+    let mut child = command
+        .args(["-f", "mpegts", "pipe:1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| {
+            error!("Unable to start {}: {}", ffmpeg, e);
+            AppError::InternalServerError
+        })?;
+    info!(
+        "Transcoding with profile {} (ffmpeg pid: {})",
+        name,
+        child.id().unwrap_or_default()
+    );
+
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    // Feed the input to ffmpeg. When the input ends, stdin is closed and ffmpeg finishes.
+    let writer = rt::spawn(async move {
+        futures::pin_mut!(input);
+        while let Some(chunk) = input.next().await {
+            if stdin.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let profile_name = name.to_owned();
+    let logger = rt::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            warn!("ffmpeg ({}): {}", profile_name, line);
+        }
+    });
+
+    let transcoder = Transcoder {
+        profile: name.to_owned(),
+        child,
+        tasks: vec![writer, logger],
+    };
+
+    Ok(stream::unfold(
+        (stdout, transcoder),
+        |(mut stdout, transcoder): (ChildStdout, Transcoder)| async move {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            match stdout.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buffer.truncate(n);
+                    Some((Bytes::from(buffer), (stdout, transcoder)))
+                }
+                Err(e) => {
+                    warn!("Unable to read from ffmpeg ({}): {}", transcoder.profile, e);
+                    None
+                }
+            }
+        },
+    ))
+}
+
+impl Drop for Transcoder {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        if self.child.start_kill().is_ok() {
+            info!(
+                "Stopped transcoding with profile {} (ffmpeg pid: {})",
+                self.profile,
+                self.child.id().unwrap_or_default()
+            );
+        }
+    }
+}
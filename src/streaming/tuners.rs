@@ -0,0 +1,102 @@
+use crate::{config::Config, errors::AppError, service::station_provider::StationProvider};
+use futures::{Stream, StreamExt};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+// Name of the tuner pool when tuners are shared by all services
+static GLOBAL_POOL: &str = "global";
+
+/// Hands out tuners, making sure no more than `tuner_count` streams are active at the same time.
+/// Tuners are either counted globally, or per `LocastService` when `tuners_per_service` is set.
+pub struct Tuners {
+    config: Arc<Config>,
+    in_use: Arc<Mutex<HashMap<String, u8>>>,
+}
+
+/// An allocated tuner. The tuner is released when this is dropped.
+pub struct Tuner {
+    pool: String,
+    in_use: Arc<Mutex<HashMap<String, u8>>>,
+}
+
+impl Tuners {
+    pub fn new(config: Arc<Config>) -> Tuners {
+        Tuners {
+            config,
+            in_use: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Allocate a tuner for station `id`. Returns `AppError::AllTunersBusy` when all tuners of
+    /// the pool the station belongs to are in use.
+    pub async fn allocate<T: StationProvider>(
+        &self,
+        id: &str,
+        service: &T,
+    ) -> Result<Tuner, AppError> {
+        let pool = self.pool(id, service).await?;
+        let mut in_use = self.in_use.lock().unwrap();
+        let count = in_use.entry(pool.to_owned()).or_insert(0);
+        if *count >= self.config.tuner_count {
+            warn!("All tuners busy ({}), rejecting station {}", pool, id);
+            return Err(AppError::AllTunersBusy);
+        }
+        *count += 1;
+        info!(
+            "Allocated tuner for station {} ({}: {}/{} in use)",
+            id, pool, count, self.config.tuner_count
+        );
+        Ok(Tuner {
+            pool,
+            in_use: self.in_use.clone(),
+        })
+    }
+
+    /// Check if a tuner is available for station `id` without allocating one
+    pub async fn available<T: StationProvider>(
+        &self,
+        id: &str,
+        service: &T,
+    ) -> Result<(), AppError> {
+        let pool = self.pool(id, service).await?;
+        match self.in_use.lock().unwrap().get(&pool) {
+            Some(count) if *count >= self.config.tuner_count => {
+                warn!("All tuners busy ({}), rejecting station {}", pool, id);
+                Err(AppError::AllTunersBusy)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The pool that tuners for station `id` are taken from
+    async fn pool<T: StationProvider>(&self, id: &str, service: &T) -> Result<String, AppError> {
+        if self.config.tuners_per_service {
+            service.station_service_uuid(id).await
+        } else {
+            Ok(GLOBAL_POOL.to_owned())
+        }
+    }
+}
+
+impl Tuner {
+    /// Keep the tuner allocated for as long as `stream` is alive, i.e. until the client
+    /// disconnects or the stream ends.
+    pub fn hold<S: Stream>(self, stream: S) -> impl Stream<Item = S::Item> {
+        stream.map(move |item| {
+            let _tuner = &self;
+            item
+        })
+    }
+}
+
+impl Drop for Tuner {
+    fn drop(&mut self) {
+        let mut in_use = self.in_use.lock().unwrap();
+        if let Some(count) = in_use.get_mut(&self.pool) {
+            *count = count.saturating_sub(1);
+            info!("Released tuner ({}: {} in use)", self.pool, count);
+        }
+    }
+}
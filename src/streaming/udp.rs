@@ -0,0 +1,212 @@
+use super::{align, hub::Hubs, StreamStatus};
+use crate::{
+    config::{Config, UdpOutput},
+    service::station_provider::StationProvider,
+};
+use bytes::Bytes;
+use futures::{
+    channel::mpsc::{self, TryRecvError},
+    Stream, StreamExt,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    net::UdpSocket,
+    time::{interval, sleep, Duration, Instant},
+};
+
+// Every datagram carries 7 MPEG-TS packets, which is what fits in an Ethernet frame and what IPTV
+// set-top boxes expect
+static DATAGRAM_SIZE: usize = 7 * 188;
+
+// How often datagrams are sent
+static SEND_INTERVAL: Duration = Duration::from_millis(10);
+
+// When more than this many seconds of video are waiting, sending speeds up by `CATCH_UP`. This
+// happens after a stream was recovered, when a burst of segments comes in at once.
+static MAX_BACKLOG: f64 = 10.0;
+static CATCH_UP: f64 = 1.5;
+
+/// Start pushing all stations in `udp_outputs` that are served by `service`. Streams go through
+/// `hubs`, so viewers of the same station share the upstream session with the output.
+pub fn start<T: 'static + StationProvider + Send + Sync + Clone>(
+    config: Arc<Config>,
+    hubs: Arc<Hubs>,
+    service: T,
+) {
+    for output in config.udp_outputs.iter().flatten() {
+        let output = output.clone();
+        let config = config.clone();
+        let hubs = hubs.clone();
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            // Stations are only pushed by the service that serves them
+            let served = service
+                .stations()
+                .await
+                .lock()
+                .await
+                .iter()
+                .any(|s| s.id.to_string() == output.station);
+            if !served {
+                return;
+            }
+
+            let socket = match bind(&config, &output.address).await {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("Unable to send UDP to {}: {}", output.address, e);
+                    return;
+                }
+            };
+            info!(
+                "Pushing station {} to udp://{}",
+                output.station, output.address
+            );
+
+            loop {
+                match hubs
+                    .subscribe(
+                        &output.station,
+                        &service,
+                        None,
+                        config.variant_policy.clone(),
+                        config.stream_start,
+                    )
+                    .await
+                {
+                    Ok((upstream, status)) => {
+                        push(&socket, &output, upstream, status).await;
+                        warn!("UDP output of station {} ended, restarting", output.station);
+                    }
+                    Err(e) => warn!(
+                        "Unable to push station {} to udp://{}: {}",
+                        output.station, output.address, e
+                    ),
+                }
+                sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+}
+
+/// Create a socket to send datagrams to `address` from
+async fn bind(config: &Config, address: &SocketAddr) -> std::io::Result<UdpSocket> {
+    let local = match address {
+        SocketAddr::V4(_) => format!("{}:0", config.bind_address),
+        SocketAddr::V6(_) => "[::]:0".to_owned(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    if address.ip().is_multicast() {
+        match address {
+            SocketAddr::V4(_) => socket.set_multicast_ttl_v4(1)?,
+            SocketAddr::V6(_) => {}
+        }
+    } else {
+        socket.set_broadcast(true)?;
+    }
+    Ok(socket)
+}
+
+/// Send `upstream` to the output address until it ends. Datagrams are sent at the rate the video
+/// plays at, which is measured by comparing the bytes received with the seconds of video that were
+/// served according to `status`. Nothing is sent until the first segment has been served, so there's
+/// always about a segment of video to smooth out hiccups.
+async fn push<S: 'static + Stream<Item = Bytes> + Send>(
+    socket: &UdpSocket,
+    output: &UdpOutput,
+    upstream: S,
+    status: Arc<Mutex<StreamStatus>>,
+) {
+    // Read the upstream in a separate task, so sending doesn't wait for segments to come in
+    let (sender, mut chunks) = mpsc::unbounded();
+    let reader = tokio::spawn(async move {
+        futures::pin_mut!(upstream);
+        while let Some(chunk) = upstream.next().await {
+            if sender.unbounded_send(chunk).is_err() {
+                break;
+            }
+        }
+    });
+
+    let start_seconds = status.lock().unwrap().seconds_served;
+    let mut served = start_seconds;
+    let mut pending: Vec<u8> = Vec::new();
+    let mut received: u64 = 0;
+    let mut rate: Option<f64> = None;
+    // How many bytes may be sent right now
+    let mut credit = 0.0;
+    let mut last_tick = Instant::now();
+    let mut ended = false;
+
+    let mut ticker = interval(SEND_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let elapsed = last_tick.elapsed().as_secs_f64();
+        last_tick = Instant::now();
+
+        while !ended {
+            match chunks.try_recv() {
+                Ok(chunk) => {
+                    received += chunk.len() as u64;
+                    pending.extend_from_slice(&chunk);
+                }
+                Err(TryRecvError::Closed) => ended = true,
+                // Nothing new came in
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        // Update the rate every time a segment has been served
+        let seconds = status.lock().unwrap().seconds_served;
+        if seconds > served {
+            served = seconds;
+            let new_rate = received as f64 / (seconds - start_seconds) as f64;
+            if rate.is_none() {
+                debug!(
+                    "UDP output of station {} - sending at {:.0} kbit/s",
+                    output.station,
+                    new_rate * 8.0 / 1000.0
+                );
+            }
+            rate = Some(new_rate);
+        }
+
+        let rate = match rate {
+            Some(r) => r,
+            None if ended => break,
+            None => continue,
+        };
+        let pace = if pending.len() as f64 > rate * MAX_BACKLOG {
+            rate * CATCH_UP
+        } else {
+            rate
+        };
+        credit += pace * elapsed;
+
+        align(&mut pending);
+        let mut sent = 0;
+        while credit >= DATAGRAM_SIZE as f64 && pending.len() - sent >= DATAGRAM_SIZE {
+            let datagram = &pending[sent..sent + DATAGRAM_SIZE];
+            if let Err(e) = socket.send_to(datagram, output.address).await {
+                warn!("Unable to send to udp://{}: {}", output.address, e);
+            }
+            sent += DATAGRAM_SIZE;
+            credit -= DATAGRAM_SIZE as f64;
+        }
+        pending.drain(..sent);
+
+        if pending.len() < DATAGRAM_SIZE {
+            if ended {
+                break;
+            }
+            // Don't make up for the time we ran dry by sending a burst later on
+            credit = 0.0;
+        }
+    }
+
+    reader.abort();
+}
@@ -7,8 +7,9 @@ use reqwest::{
     header::{HeaderMap, HeaderValue},
     Response,
 };
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
-use std::time::Duration;
+use std::{fs, path::Path, time::Duration};
 
 pub trait Or {
     /// Return `self` if it's not empty, otherwise `other`
@@ -89,6 +90,12 @@ pub fn hdhr_checksum(device_id: usize) -> usize {
     checksum
 }
 
+/// Make `device_id` a valid HDHomeRun device ID by replacing its last hex digit with the checksum
+pub fn hdhr_valid_device_id(device_id: u32) -> u32 {
+    let device_id = (device_id & !0xF) as usize;
+    (device_id | hdhr_checksum(device_id)) as u32
+}
+
 /// Return only the name for a station (e.g. 2.1 CBS --> CBS)
 pub fn name_only(value: &str) -> &str {
     match Regex::new(r"\d+\.\d+ (.+)").unwrap().captures(value) {
@@ -151,3 +158,40 @@ pub fn quality(properties: &str) -> String {
         "SD".to_owned()
     }
 }
+
+/// Load `what` from a JSON file in the cache directory. If the file doesn't exist or is invalid,
+/// the default is returned.
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path, what: &str) -> T {
+    match fs::read_to_string(path) {
+        Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+            warn!("Ignoring invalid {} in {:?}: {}", what, path, e);
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
+
+/// Store `what` as a JSON file in the cache directory. Failures are logged, but not fatal.
+pub fn save_json<T: Serialize>(path: &Path, value: &T, what: &str) {
+    let saved = serde_json::to_string_pretty(value)
+        .map_err(|e| e.to_string())
+        .and_then(|j| fs::write(path, j).map_err(|e| e.to_string()));
+    if let Err(e) = saved {
+        warn!("Unable to store {} in {:?}: {}", what, path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_device_ids() {
+        for id in (0..u32::MAX).step_by(0x0fed_cba9) {
+            let valid = hdhr_valid_device_id(id);
+            // Only the last hex digit is replaced, by the digit that makes the checksum 0
+            assert_eq!(valid & !0xf, id & !0xf);
+            assert_eq!(hdhr_checksum(valid as usize), 0, "{:x}", valid);
+        }
+    }
+}